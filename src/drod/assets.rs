@@ -0,0 +1,166 @@
+use super::level_info::{LevelErrors, Parser};
+use super::model::{EquipStat, MonsterFlag, MonsterStat, PlayerFlag};
+
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+// Named monsters and equipment that level files can refer to
+#[derive(Clone, Default)]
+pub struct Catalog {
+    monsters: HashMap<String, MonsterStat>,
+    equipment: HashMap<String, EquipStat>,
+}
+
+impl Catalog {
+    // Monsters and equipment of the official game
+    pub fn builtin() -> Self {
+        let mut catalog = Self::default();
+        let none = MonsterFlag::empty();
+        let goblin = MonsterFlag::GOBLIN_WEAKNESS;
+        let wyrm = MonsterFlag::WYRM_WEAKNESS;
+        let behind = MonsterFlag::SURPRISED_FROM_BEHIND;
+        let one_hit = MonsterFlag::ONE_HIT;
+
+        catalog.add_monster("Brain", none, 35, 9, 1, 1);
+        catalog.add_monster("Roach", none, 45, 20, 2, 2);
+        catalog.add_monster("Wraithwing", none, 35, 38, 3, 3);
+        catalog.add_monster("EvilEye", none, 60, 32, 8, 5);
+        catalog.add_monster("RoachQueen", none, 50, 42, 6, 6);
+        catalog.add_monster("Spider", none, 55, 52, 12, 8);
+        catalog.add_monster("MudBaby", none, 130, 60, 3, 8);
+        catalog.add_monster("Antlion", none, 60, 100, 8, 12);
+        catalog.add_monster("MudMother", none, 50, 48, 22, 12);
+        catalog.add_monster("TarMother", none, 100, 180, 110, 100);
+        catalog.add_monster("GelMother", none, 180, 460, 360, 200);
+        catalog.add_monster("GrayMan", none, 260, 85, 5, 18);
+        catalog.add_monster("MadEye", none, 100, 95, 30, 22);
+        catalog.add_monster("Neather", none, 100, 65, 15, 25);
+        catalog.add_monster("RockGolem", none, 20, 100, 68, 28);
+        catalog.add_monster("Goblin", goblin, 320, 120, 15, 30);
+        catalog.add_monster("TarBaby", none, 320, 140, 20, 30);
+        catalog.add_monster("Soulless", none, 220, 180, 30, 35);
+        catalog.add_monster("Mimic", none, 210, 200, 65, 45);
+        catalog.add_monster("Swordsman", none, 100, 680, 50, 55);
+        catalog.add_monster("RedGuard", none, 160, 230, 105, 65);
+        catalog.add_monster("GelBaby", none, 360, 310, 20, 40);
+        catalog.add_monster("Fegundo", none, 200, 390, 90, 50);
+        catalog.add_monster("WaterSkipper", none, 220, 370, 110, 80);
+        catalog.add_monster("Seep", none, 200, 380, 130, 90);
+        catalog.add_monster("Pirate", none, 180, 430, 210, 120);
+        catalog.add_monster("Aumtlich", none, 230, 450, 100, 100);
+        catalog.add_monster("Wubba", none, 10, 0, 320, 100);
+        catalog.add_monster("GoblinKing", goblin, 400, 199, 66, 144);
+        catalog.add_monster("Slayer", none, 4500, 560, 310, 1000);
+        catalog.add_monster("RockGiant", none, 800, 500, 100, 500);
+        catalog.add_monster("Rattlesnake", wyrm, 1200, 180, 20, 100);
+        catalog.add_monster("Adder", wyrm, 1500, 600, 250, 800);
+        catalog.add_monster("Serpent", wyrm, 2500, 550, 350, 900);
+
+        catalog.add_monster("RoachEgg", none, 1, 0, 23, 0);
+        catalog.add_monster("EvilEyeBack", behind, 60, 32, 8, 5);
+        catalog.add_monster("EvilEyeHit", one_hit, 60, 32, 8, 5);
+        catalog.add_monster("MadEyeBack", behind, 100, 95, 30, 22);
+        catalog.add_monster("MadEyeHit", one_hit, 100, 95, 30, 22);
+
+        let weapon = PlayerFlag::HAS_WEAPON;
+        let lucky = PlayerFlag::HAS_WEAPON | PlayerFlag::DOUBLE_GR_WEAPON;
+        let biter = PlayerFlag::HAS_WEAPON | PlayerFlag::DOUBLE_ATK_AGAINST_GOBLIN;
+        let smiter = PlayerFlag::HAS_WEAPON | PlayerFlag::DOUBLE_ATK_AGAINST_WYRM;
+        let shield = PlayerFlag::empty();
+        let greckle = PlayerFlag::DOUBLE_GR_ACCESSORY;
+        let vip = PlayerFlag::DOUBLE_REP_ACCESSORY;
+
+        catalog.add_equipment("WoodenBlade", weapon, 10, 0);
+        catalog.add_equipment("LuckyBlade", lucky, 10, 0);
+        catalog.add_equipment("ShortSword", weapon, 30, 0);
+        catalog.add_equipment("GoblinBiter", biter, 30, 0);
+        catalog.add_equipment("LongSword", weapon, 70, 0);
+        catalog.add_equipment("Hook", weapon, 120, 0);
+        catalog.add_equipment("WyrmSmiter", smiter, 120, 0);
+        catalog.add_equipment("ReallyBigSword", weapon, 220, 0);
+
+        catalog.add_equipment("WoodenShield", shield, 0, 10);
+        catalog.add_equipment("BronzeShield", shield, 0, 30);
+        catalog.add_equipment("SteelShield", shield, 0, 70);
+        catalog.add_equipment("KnightShield", shield, 0, 120);
+        catalog.add_equipment("OremiteShield", shield, 0, 220);
+
+        catalog.add_equipment("LuckyGreckle", greckle, 0, 0);
+        catalog.add_equipment("VIPCard", vip, 0, 0);
+
+        catalog
+    }
+
+    // Add entries from a catalog file, replacing entries with the same name
+    pub fn load(&mut self, data: &Value) -> Result<(), LevelErrors> {
+        let mut parser = Parser::new(self.clone());
+        parser.catalog(data, "$");
+        *self = parser.into_catalog()?;
+        Ok(())
+    }
+
+    fn add_monster(&mut self, name: &str, flag: MonsterFlag, hp: i32, atk: i16, def: i16, gr: i16) {
+        let monster = MonsterStat {
+            flag,
+            hp,
+            atk,
+            def,
+            gr,
+            ..Default::default()
+        };
+        self.insert_monster(name.to_owned(), monster);
+    }
+
+    fn add_equipment(&mut self, name: &str, flag: PlayerFlag, atk: i16, def: i16) {
+        let equip = EquipStat { flag, atk, def };
+        self.insert_equipment(name.to_owned(), equip);
+    }
+
+    pub(super) fn insert_monster(&mut self, name: String, monster: MonsterStat) {
+        self.monsters.insert(name, monster);
+    }
+
+    pub(super) fn insert_equipment(&mut self, name: String, equip: EquipStat) {
+        self.equipment.insert(name, equip);
+    }
+
+    pub(super) fn monster(&self, name: &str) -> Option<&MonsterStat> {
+        self.monsters.get(name)
+    }
+
+    pub(super) fn equipment(&self, name: &str) -> Option<&EquipStat> {
+        self.equipment.get(name)
+    }
+
+    // First name in alphabetical order of the monster with the given stats
+    pub(super) fn monster_name(&self, monster: &MonsterStat) -> Option<&str> {
+        self.monsters
+            .iter()
+            .filter(|(_, other)| *other == monster)
+            .map(|(name, _)| name.as_str())
+            .min()
+    }
+
+    // First name in alphabetical order of the equipment with the given stats
+    pub(super) fn equipment_name(&self, equip: &EquipStat) -> Option<&str> {
+        self.equipment
+            .iter()
+            .filter(|(_, other)| *other == equip)
+            .map(|(name, _)| name.as_str())
+            .min()
+    }
+}
+
+// Entries in the order of their names, as the maps have no order of their own
+impl Hash for Catalog {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut monsters: Vec<_> = self.monsters.iter().collect();
+        monsters.sort_by(|a, b| a.0.cmp(b.0));
+        monsters.hash(state);
+        let mut equipment: Vec<_> = self.equipment.iter().collect();
+        equipment.sort_by(|a, b| a.0.cmp(b.0));
+        equipment.hash(state);
+    }
+}
@@ -0,0 +1,238 @@
+use super::VertexIDType;
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not};
+
+// Set of room ids that fits in N 64-bit words
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub(super) struct BitSet<const N: usize>([u64; N]);
+
+impl<const N: usize> BitSet<N> {
+    pub(super) const CAPACITY: usize = 64 * N;
+
+    pub(super) fn new() -> Self {
+        Self([0; N])
+    }
+
+    pub(super) fn from_words(words: [u64; N]) -> Self {
+        Self(words)
+    }
+
+    pub(super) fn words(&self) -> &[u64; N] {
+        &self.0
+    }
+
+    // Positions past the end, such as the id of no room, are never set
+    pub(super) fn get_bit(&self, index: usize) -> bool {
+        index < Self::CAPACITY && self.0[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    pub(super) fn set_bit(&mut self, index: usize, value: bool) {
+        if value {
+            self.0[index / 64] |= 1 << (index % 64);
+        } else {
+            self.0[index / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    #[cfg(feature = "closed-level")]
+    pub(super) fn reset(&mut self) {
+        self.0 = [0; N];
+    }
+
+    pub(super) fn any(&self) -> bool {
+        self.0.iter().any(|&word| word != 0)
+    }
+
+    pub(super) fn none(&self) -> bool {
+        !self.any()
+    }
+
+    // Position of the lowest enabled bit, or CAPACITY if there is none
+    pub(super) fn first_set(&self) -> usize {
+        for (i, &word) in self.0.iter().enumerate() {
+            if word != 0 {
+                return i * 64 + word.trailing_zeros() as usize;
+            }
+        }
+        Self::CAPACITY
+    }
+
+    pub(super) fn get_weight(&self) -> u32 {
+        self.0.iter().map(|word| word.count_ones()).sum()
+    }
+}
+
+// Number of words of the narrowest room sets that hold every room of a level
+pub(super) fn word_count(room_count: usize) -> usize {
+    match room_count {
+        0..=64 => 1,
+        65..=128 => 2,
+        _ => 4,
+    }
+}
+
+impl<const N: usize> Default for BitSet<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Hexadecimal number with bit 0 as the lowest bit
+impl<const N: usize> Display for BitSet<N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut words = self.0.iter().rev().skip_while(|&&word| word == 0);
+        match words.next() {
+            Some(word) => write!(f, "0x{:x}", word)?,
+            None => return write!(f, "0x0"),
+        }
+        for word in words {
+            write!(f, "{:016x}", word)?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Not for BitSet<N> {
+    type Output = Self;
+
+    fn not(mut self) -> Self {
+        for word in &mut self.0 {
+            *word = !*word;
+        }
+        self
+    }
+}
+
+macro_rules! impl_bit_op {
+    ($op:ident, $fn:ident, $op_assign:ident, $fn_assign:ident) => {
+        impl<const N: usize> $op_assign for BitSet<N> {
+            fn $fn_assign(&mut self, other: Self) {
+                for (word, other) in self.0.iter_mut().zip(other.0.iter()) {
+                    word.$fn_assign(other);
+                }
+            }
+        }
+
+        impl<const N: usize> $op for BitSet<N> {
+            type Output = Self;
+
+            fn $fn(mut self, other: Self) -> Self {
+                self.$fn_assign(other);
+                self
+            }
+        }
+    };
+}
+
+impl_bit_op!(BitAnd, bitand, BitAndAssign, bitand_assign);
+impl_bit_op!(BitOr, bitor, BitOrAssign, bitor_assign);
+impl_bit_op!(BitXor, bitxor, BitXorAssign, bitxor_assign);
+
+// An iterator for BitSet that returns the position of each enabled bit in the set
+pub(super) struct BitSetIter<const N: usize>(BitSet<N>);
+
+impl<const N: usize> From<BitSet<N>> for BitSetIter<N> {
+    fn from(bitset: BitSet<N>) -> Self {
+        Self(bitset)
+    }
+}
+
+impl<const N: usize> Iterator for BitSetIter<N> {
+    type Item = VertexIDType;
+
+    // Return position of next enabled bit in set
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.none() {
+            None
+        } else {
+            let first_set = self.0.first_set();
+            self.0.set_bit(first_set, false);
+            Some(first_set as VertexIDType)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rooms on either side of each word boundary, and the last supported room
+    const BOUNDARY: [usize; 5] = [63, 64, 127, 128, 255];
+
+    fn boundary_set() -> BitSet<4> {
+        let mut set = BitSet::new();
+        for &index in &BOUNDARY {
+            set.set_bit(index, true);
+        }
+        set
+    }
+
+    #[test]
+    fn bits_at_word_boundaries() {
+        let set = boundary_set();
+        for index in 0..BitSet::<4>::CAPACITY {
+            assert_eq!(set.get_bit(index), BOUNDARY.contains(&index), "{}", index);
+        }
+        assert_eq!(set.get_weight(), 5);
+        assert_eq!(set.words(), &[1 << 63, 1 | 1 << 63, 1, 1 << 63]);
+
+        let mut set = set;
+        set.set_bit(64, false);
+        set.set_bit(128, false);
+        assert_eq!(set.words(), &[1 << 63, 1 << 63, 0, 1 << 63]);
+    }
+
+    #[test]
+    fn first_set_and_iter_cross_words() {
+        let set = boundary_set();
+        assert_eq!(set.first_set(), 63);
+        let ids: Vec<VertexIDType> = BitSetIter::from(set).collect();
+        assert_eq!(ids, [63, 64, 127, 128, 255]);
+
+        let mut set = BitSet::<2>::new();
+        assert_eq!(set.first_set(), BitSet::<2>::CAPACITY);
+        set.set_bit(127, true);
+        assert_eq!(set.first_set(), 127);
+    }
+
+    #[test]
+    fn positions_past_the_end() {
+        let set = !BitSet::<1>::new();
+        assert!(set.get_bit(63));
+        assert!(!set.get_bit(64));
+        assert!(!set.get_bit(VertexIDType::MAX as usize + 1));
+    }
+
+    #[test]
+    fn operators_work_on_every_word() {
+        let set = boundary_set();
+        assert!((set & !set).none());
+        assert!((set ^ set).none());
+        assert_eq!((set | !set).get_weight(), 256);
+        assert_eq!((!set).get_weight(), 251);
+    }
+
+    #[test]
+    fn display_pads_lower_words() {
+        assert_eq!(BitSet::<2>::new().to_string(), "0x0");
+        let mut set = BitSet::<2>::new();
+        set.set_bit(64, true);
+        set.set_bit(0, true);
+        assert_eq!(set.to_string(), "0x10000000000000001");
+    }
+
+    #[test]
+    fn word_count_from_room_count() {
+        assert_eq!(word_count(0), 1);
+        assert_eq!(word_count(64), 1);
+        assert_eq!(word_count(65), 2);
+        assert_eq!(word_count(128), 2);
+        assert_eq!(word_count(129), 4);
+        assert_eq!(word_count(256), 4);
+        for room_count in 1..=256 {
+            assert!(word_count(room_count) * 64 >= room_count);
+        }
+    }
+}
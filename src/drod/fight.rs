@@ -0,0 +1,297 @@
+use super::assets::Catalog;
+use super::level_info::{LevelErrors, LevelInfo, Parser};
+use super::model::{FightStat, MonsterStat, PlayerCombat};
+
+use serde_json::{Map, Value};
+
+use std::io;
+use std::io::Write;
+
+// Single fight against a monster outside of any level, to look up what it costs
+pub struct FightInfo {
+    player: PlayerCombat,
+    monster: MonsterStat,
+}
+
+impl FightInfo {
+    // Parse fight that may refer to monsters and equipment in the given catalog.
+    // ATK and DEF of the player include the equipment, which adds its flags.
+    pub fn new(data: Value, catalog: &Catalog) -> Result<Self, LevelErrors> {
+        let mut parser = Parser::new(catalog.clone());
+        let empty = Map::new();
+        let object = parser.object(&data, "$").unwrap_or(&empty);
+        parser.check_keys(object, &["player", "equip", "monster"], "$");
+
+        let mut player = match object.get("player") {
+            Some(value) => parser.player_stat(value, "$.player").combat,
+            None => PlayerCombat::default(),
+        };
+        if let Some(value) = object.get("equip") {
+            for (i, value) in parser.array(value, "$.equip").iter().enumerate() {
+                if let Some(equip) = parser.equipment(value, &format!("$.equip[{}]", i)) {
+                    player.flag |= equip.flag;
+                    player.equip.flag |= equip.flag;
+                    player.equip.atk += equip.atk;
+                    player.equip.def += equip.def;
+                }
+            }
+        }
+        let monster = match parser.get(object, "monster", "$") {
+            Some(value) => parser.monster(value, "$.monster"),
+            None => None,
+        };
+        let fight = Self {
+            player,
+            monster: monster.unwrap_or_default(),
+        };
+        parser.finish(fight)
+    }
+
+    // Outcome of the fight, or None when the player cannot win
+    pub fn outcome(&self) -> Option<FightStat> {
+        self.monster.fight(&self.player)
+    }
+
+    // Lowest values of ATK above the current one at which the HP cost changes
+    pub fn atk_breakpoints(&self, count: usize) -> Vec<(i16, Option<FightStat>)> {
+        self.breakpoints(count, self.player.atk, |player, atk| player.atk = atk)
+    }
+
+    // Lowest values of DEF above the current one at which the HP cost changes
+    pub fn def_breakpoints(&self, count: usize) -> Vec<(i16, Option<FightStat>)> {
+        self.breakpoints(count, self.player.def, |player, def| player.def = def)
+    }
+
+    fn breakpoints(
+        &self,
+        count: usize,
+        start: i16,
+        set_stat: fn(&mut PlayerCombat, i16),
+    ) -> Vec<(i16, Option<FightStat>)> {
+        let hp_cost = |outcome: &Option<FightStat>| outcome.as_ref().map(|fight| fight.hp_cost);
+        let mut player = self.player.clone();
+        let mut last = self.outcome();
+        let mut breakpoints = Vec::new();
+        for value in start.saturating_add(1)..=i16::MAX {
+            if breakpoints.len() >= count {
+                break;
+            }
+            set_stat(&mut player, value);
+            let outcome = self.monster.fight(&player);
+            if hp_cost(&outcome) != hp_cost(&last) {
+                breakpoints.push((value, outcome.clone()));
+                last = outcome;
+            }
+        }
+        breakpoints
+    }
+
+    // Print outcome of the fight followed by the given number of ATK and DEF breakpoints
+    pub fn print(&self, writer: &mut dyn Write, count: usize) -> io::Result<()> {
+        write_outcome(writer, &self.outcome())?;
+        writeln!(writer, "atk breakpoints:")?;
+        for (atk, outcome) in self.atk_breakpoints(count) {
+            write!(writer, "  atk: {}, ", atk)?;
+            write_outcome(writer, &outcome)?;
+        }
+        writeln!(writer, "def breakpoints:")?;
+        for (def, outcome) in self.def_breakpoints(count) {
+            write!(writer, "  def: {}, ", def)?;
+            write_outcome(writer, &outcome)?;
+        }
+        Ok(())
+    }
+}
+
+// Write the ATK and DEF breakpoints of every monster of the level as a CSV table,
+// for the initial player of the level with ATK and DEF replaced when given
+pub fn write_breakpoints(
+    level_info: &LevelInfo,
+    atk: Option<i16>,
+    def: Option<i16>,
+    count: usize,
+    writer: &mut dyn Write,
+) -> io::Result<()> {
+    let mut player = level_info.init_player().combat;
+    player.atk = atk.unwrap_or(player.atk);
+    player.def = def.unwrap_or(player.def);
+    writeln!(
+        writer,
+        "monster,hits,hp_cost,stat,value,new_hits,new_hp_cost,hp_saved"
+    )?;
+    for (name, monster) in level_info.monsters() {
+        let fight = FightInfo {
+            player: player.clone(),
+            monster: monster.clone(),
+        };
+        // Fields are left empty for fights the player cannot win
+        let outcome = fight.outcome();
+        let (hits, hp_cost) = outcome_fields(&outcome);
+        let atk_rows = fight
+            .atk_breakpoints(count)
+            .into_iter()
+            .map(|row| ("atk", row));
+        let def_rows = fight
+            .def_breakpoints(count)
+            .into_iter()
+            .map(|row| ("def", row));
+        for (stat, (value, new_outcome)) in atk_rows.chain(def_rows) {
+            let (new_hits, new_hp_cost) = outcome_fields(&new_outcome);
+            let hp_saved = match (&outcome, &new_outcome) {
+                (Some(fight), Some(new_fight)) => (fight.hp_cost - new_fight.hp_cost).to_string(),
+                _ => String::new(),
+            };
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{}",
+                csv_field(name),
+                hits,
+                hp_cost,
+                stat,
+                value,
+                new_hits,
+                new_hp_cost,
+                hp_saved
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn outcome_fields(outcome: &Option<FightStat>) -> (String, String) {
+    match outcome {
+        Some(fight) => (fight.hits.to_string(), fight.hp_cost.to_string()),
+        None => (String::new(), String::new()),
+    }
+}
+
+// Quote field containing separators or quotes
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn write_outcome(writer: &mut dyn Write, outcome: &Option<FightStat>) -> io::Result<()> {
+    match outcome {
+        Some(fight) => writeln!(writer, "{}", fight),
+        None => writeln!(writer, "cannot win"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drod::model::{MonsterFlag, PlayerFlag};
+
+    fn fight(monster: &MonsterStat, atk: i16, def: i16) -> FightInfo {
+        FightInfo {
+            player: PlayerCombat {
+                flag: PlayerFlag::HAS_WEAPON,
+                atk,
+                def,
+                ..Default::default()
+            },
+            monster: monster.clone(),
+        }
+    }
+
+    fn monster(hp: i32, atk: i16, def: i16) -> MonsterStat {
+        MonsterStat {
+            hp,
+            atk,
+            def,
+            ..Default::default()
+        }
+    }
+
+    fn hits(fight: &FightInfo) -> Option<i32> {
+        fight.outcome().map(|fight| fight.hits)
+    }
+
+    fn hp_cost(fight: &FightInfo) -> Option<i32> {
+        fight.outcome().map(|fight| fight.hp_cost)
+    }
+
+    #[test]
+    fn breakpoints_of_plain_monster() {
+        // 25 damage takes 4 strikes, 34 takes 3, 50 takes 2 and 100 takes 1,
+        // while every point of DEF blocks a point of each of the 3 hits
+        let fight = fight(&monster(100, 20, 5), 30, 10);
+        let atk: Vec<_> = fight
+            .atk_breakpoints(5)
+            .into_iter()
+            .map(|(atk, _)| atk)
+            .collect();
+        assert_eq!(atk, [39, 55, 105]);
+        let def: Vec<_> = fight
+            .def_breakpoints(5)
+            .into_iter()
+            .map(|(def, _)| def)
+            .collect();
+        assert_eq!(def, [11, 12, 13, 14, 15]);
+    }
+
+    // ATK breakpoints change the number of hits and DEF breakpoints the damage of each hit.
+    // Values between two breakpoints cost as much HP as the lower one.
+    #[test]
+    fn breakpoints_change_the_fight() {
+        let monsters = [
+            monster(100, 20, 5),
+            MonsterStat {
+                regen: 5,
+                ..monster(200, 40, 10)
+            },
+            MonsterStat {
+                flag: MonsterFlag::ATTACK_FIRST | MonsterFlag::HAS_WEAPON,
+                pierce: 50,
+                ..monster(80, 35, 8)
+            },
+            MonsterStat {
+                drain_atk: 2,
+                drain_def: 3,
+                ..monster(60, 30, 5)
+            },
+        ];
+        for monster in &monsters {
+            let (atk, def) = (20, 5);
+            let base = fight(monster, atk, def);
+            let (atk_breakpoints, def_breakpoints) =
+                (base.atk_breakpoints(5), base.def_breakpoints(5));
+            assert!(base.outcome().is_some());
+            assert_eq!((atk_breakpoints.len(), def_breakpoints.len()), (5, 5));
+
+            let mut last = atk;
+            for (value, outcome) in atk_breakpoints {
+                let at = fight(monster, value, def);
+                assert_eq!(outcome.map(|fight| fight.hits), hits(&at));
+                assert_ne!(hits(&at), hits(&fight(monster, value - 1, def)));
+                for below in last + 1..value {
+                    assert_eq!(
+                        hp_cost(&fight(monster, below, def)),
+                        hp_cost(&fight(monster, last, def))
+                    );
+                }
+                last = value;
+            }
+
+            let mut last = def;
+            for (value, outcome) in def_breakpoints {
+                let at = fight(monster, atk, value);
+                let below = fight(monster, atk, value - 1);
+                assert_eq!(outcome.map(|fight| fight.hp_cost), hp_cost(&at));
+                assert_eq!(hits(&at), hits(&below));
+                assert_ne!(hp_cost(&at), hp_cost(&below));
+                for below in last + 1..value {
+                    assert_eq!(
+                        hp_cost(&fight(monster, atk, below)),
+                        hp_cost(&fight(monster, atk, last))
+                    );
+                }
+                last = value;
+            }
+        }
+    }
+}
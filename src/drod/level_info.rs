@@ -0,0 +1,1739 @@
+use super::assets::Catalog;
+use super::model::{
+    Branch, BuildError, Element, EquipStat, HpBoostStat, Level, MonsterFlag, MonsterStat,
+    PlayerCombat, PlayerFlag, PlayerStat, Room, RoomType, ScoreFunction,
+};
+use super::{
+    default_keys, Counters, MAX_CHOICE_COUNT, MAX_COUNTER_COUNT, MAX_HP_PERCENT, MAX_ITEM_COUNT,
+    MAX_KEY_COUNT, MAX_ROOM_COUNT,
+};
+
+use serde_json::{Map, Value};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::ops::BitOrAssign;
+
+// Kinds of problems found in a level file
+#[derive(Debug)]
+pub enum LevelErrorKind {
+    ExpectedType(&'static str),
+    OutOfRange(i64),
+    MissingField(String),
+    UnknownField(String),
+    UnknownFlag(String),
+    UnknownRoomType(String),
+    UnknownElement(String),
+    UnknownMonster(String),
+    UnknownEquipment(String),
+    UnknownScore(String),
+    UnknownCounter(String),
+    UnknownTemplate(String),
+    RecursiveTemplate(String),
+    AmbiguousElement,
+    NoOptions,
+    NoBranches,
+    TooManyConfigs,
+    TooManyRooms(usize),
+    TooManyCounters(usize),
+    TooManyKeys(usize),
+    TooManyItems(usize),
+    TooManyChoices(usize),
+    DuplicateKey(String),
+    DuplicateRoom(String),
+    UnknownRoom(String),
+    MissingEntrance,
+    MissingExit,
+}
+
+impl Display for LevelErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ExpectedType(name) => write!(f, "expected {}", name),
+            Self::OutOfRange(value) => write!(f, "{} is out of range", value),
+            Self::MissingField(name) => write!(f, "missing field \"{}\"", name),
+            Self::UnknownField(name) => write!(f, "unknown field \"{}\"", name),
+            Self::UnknownFlag(name) => write!(f, "unknown flag \"{}\"", name),
+            Self::UnknownRoomType(name) => write!(f, "unknown room type \"{}\"", name),
+            Self::UnknownElement(name) => write!(f, "unknown element kind \"{}\"", name),
+            Self::UnknownMonster(name) => write!(f, "unknown monster \"{}\"", name),
+            Self::UnknownEquipment(name) => write!(f, "unknown equipment \"{}\"", name),
+            Self::UnknownScore(name) => write!(f, "unknown score function \"{}\"", name),
+            Self::UnknownCounter(name) => write!(f, "counter \"{}\" is not defined", name),
+            Self::UnknownTemplate(name) => write!(f, "template \"{}\" is not defined", name),
+            Self::RecursiveTemplate(name) => write!(f, "template \"{}\" uses itself", name),
+            Self::AmbiguousElement => write!(f, "an element must have exactly one field"),
+            Self::NoOptions => write!(f, "a config choice must have at least one option"),
+            Self::NoBranches => write!(f, "a choice must have at least one branch"),
+            Self::TooManyConfigs => write!(f, "there are too many combinations of configs"),
+            Self::TooManyRooms(count) => write!(
+                f,
+                "there are {} rooms but at most {} are supported",
+                count, MAX_ROOM_COUNT
+            ),
+            Self::TooManyCounters(count) => write!(
+                f,
+                "there are {} counters but at most {} are supported",
+                count, MAX_COUNTER_COUNT
+            ),
+            Self::TooManyKeys(count) => write!(
+                f,
+                "there are {} key types but at most {} are supported",
+                count, MAX_KEY_COUNT
+            ),
+            Self::TooManyItems(count) => write!(
+                f,
+                "there are {} weapons and accessories but at most {} are supported",
+                count, MAX_ITEM_COUNT
+            ),
+            Self::TooManyChoices(count) => write!(
+                f,
+                "there are {} ways to make the choices of the room but at most {} are supported",
+                count, MAX_CHOICE_COUNT
+            ),
+            Self::DuplicateKey(name) => write!(
+                f,
+                "key type \"{}\" is defined more than once or is the name of a stat",
+                name
+            ),
+            Self::DuplicateRoom(name) => write!(f, "room \"{}\" is defined more than once", name),
+            Self::UnknownRoom(name) => write!(f, "room \"{}\" is not defined", name),
+            Self::MissingEntrance => write!(f, "the entrance room is not defined"),
+            Self::MissingExit => write!(f, "the exit room is not defined"),
+        }
+    }
+}
+
+// Problem found in a level file along with the JSON path of the offending node
+#[derive(Debug)]
+pub struct LevelError {
+    pub path: String,
+    pub kind: LevelErrorKind,
+}
+
+impl Display for LevelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.kind)
+    }
+}
+
+// Every problem found in a level file
+#[derive(Debug)]
+pub struct LevelErrors(pub Vec<LevelError>);
+
+impl Display for LevelErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "found {} problem(s) in level:", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for LevelErrors {}
+
+impl From<LevelErrors> for io::Error {
+    fn from(errors: LevelErrors) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, errors)
+    }
+}
+
+// Stats other than keys, which key types may not be named after
+const STAT_FIELDS: &[&str] = &[
+    "hp",
+    "atk",
+    "def",
+    "flag",
+    "equip_atk",
+    "equip_def",
+    "equip_flag",
+    "gr",
+    "rep",
+];
+
+// Parse level data while collecting every problem instead of stopping at the first one.
+// Nodes with problems are replaced by defaults so that parsing can continue.
+pub(super) struct Parser {
+    errors: Vec<LevelError>,
+    catalog: Catalog,
+    counters: Vec<(String, i8)>, // Name and initial value, referred to by index
+    keys: Vec<String>,           // Names of the key types, referred to by index
+    templates: HashMap<String, Value>,
+    expanded: HashMap<String, Vec<Element>>, // Templates parsed so far
+    expanding: Vec<String>,                  // Templates being parsed, to catch recursion
+}
+
+impl Parser {
+    pub(super) fn new(catalog: Catalog) -> Self {
+        Self {
+            errors: Vec::new(),
+            catalog,
+            counters: Vec::new(),
+            keys: default_keys(),
+            templates: HashMap::new(),
+            expanded: HashMap::new(),
+            expanding: Vec::new(),
+        }
+    }
+
+    // Parse stats with the key types of a level instead of the default ones
+    pub(super) fn with_keys(mut self, keys: &[String]) -> Self {
+        self.keys = keys.to_vec();
+        self
+    }
+
+    fn error(&mut self, path: &str, kind: LevelErrorKind) {
+        self.errors.push(LevelError {
+            path: path.to_owned(),
+            kind,
+        });
+    }
+
+    fn build_error(&mut self, path: &str, err: BuildError) {
+        let kind = match err {
+            BuildError::DuplicateRoom(name) => LevelErrorKind::DuplicateRoom(name),
+            BuildError::UnknownRoom(name) => LevelErrorKind::UnknownRoom(name),
+        };
+        self.error(path, kind);
+    }
+
+    pub(super) fn object<'a>(
+        &mut self,
+        value: &'a Value,
+        path: &str,
+    ) -> Option<&'a Map<String, Value>> {
+        let object = value.as_object();
+        if object.is_none() {
+            self.error(path, LevelErrorKind::ExpectedType("an object"));
+        }
+        object
+    }
+
+    pub(super) fn array<'a>(&mut self, value: &'a Value, path: &str) -> &'a [Value] {
+        match value.as_array() {
+            Some(array) => array,
+            None => {
+                self.error(path, LevelErrorKind::ExpectedType("an array"));
+                &[]
+            }
+        }
+    }
+
+    pub(super) fn str<'a>(&mut self, value: &'a Value, path: &str) -> Option<&'a str> {
+        let string = value.as_str();
+        if string.is_none() {
+            self.error(path, LevelErrorKind::ExpectedType("a string"));
+        }
+        string
+    }
+
+    fn int<T: TryFrom<i64> + Default>(&mut self, value: &Value, path: &str) -> T {
+        match value.as_i64() {
+            Some(int) => T::try_from(int).unwrap_or_else(|_| {
+                self.error(path, LevelErrorKind::OutOfRange(int));
+                T::default()
+            }),
+            None => {
+                self.error(path, LevelErrorKind::ExpectedType("an integer"));
+                T::default()
+            }
+        }
+    }
+
+    fn bool(&mut self, value: &Value, path: &str) -> bool {
+        value.as_bool().unwrap_or_else(|| {
+            self.error(path, LevelErrorKind::ExpectedType("a boolean"));
+            false
+        })
+    }
+
+    pub(super) fn get<'a>(
+        &mut self,
+        object: &'a Map<String, Value>,
+        key: &str,
+        path: &str,
+    ) -> Option<&'a Value> {
+        let value = object.get(key);
+        if value.is_none() {
+            self.error(path, LevelErrorKind::MissingField(key.to_owned()));
+        }
+        value
+    }
+
+    // Missing numeric fields default to zero
+    fn get_int<T: TryFrom<i64> + Default>(
+        &mut self,
+        object: &Map<String, Value>,
+        key: &str,
+        path: &str,
+    ) -> T {
+        match object.get(key) {
+            Some(value) => self.int(value, &format!("{}.{}", path, key)),
+            None => T::default(),
+        }
+    }
+
+    // Replace a numeric field of a catalog entry when it is given
+    fn override_int<T: TryFrom<i64> + Default>(
+        &mut self,
+        object: &Map<String, Value>,
+        key: &str,
+        path: &str,
+        target: &mut T,
+    ) {
+        if let Some(value) = object.get(key) {
+            *target = self.int(value, &format!("{}.{}", path, key));
+        }
+    }
+
+    fn get_bool(&mut self, object: &Map<String, Value>, key: &str, path: &str) -> bool {
+        match object.get(key) {
+            Some(value) => self.bool(value, &format!("{}.{}", path, key)),
+            None => false,
+        }
+    }
+
+    // Reject misspelled fields instead of silently ignoring them
+    pub(super) fn check_keys(&mut self, object: &Map<String, Value>, keys: &[&str], path: &str) {
+        for key in object.keys() {
+            if !keys.contains(&key.as_str()) {
+                self.error(
+                    &format!("{}.{}", path, key),
+                    LevelErrorKind::UnknownField(key.clone()),
+                );
+            }
+        }
+    }
+
+    // Combine a list of names into flags, reporting unknown names
+    fn flags<F: Default + BitOrAssign>(
+        &mut self,
+        object: &Map<String, Value>,
+        key: &str,
+        path: &str,
+        from_name: fn(&str) -> Option<F>,
+        unknown: fn(String) -> LevelErrorKind,
+    ) -> F {
+        let mut flag = F::default();
+        if let Some(value) = object.get(key) {
+            let path = format!("{}.{}", path, key);
+            for (i, name) in self.array(value, &path).iter().enumerate() {
+                let path = format!("{}[{}]", path, i);
+                if let Some(name) = self.str(name, &path) {
+                    match from_name(name) {
+                        Some(name_flag) => flag |= name_flag,
+                        None => self.error(&path, unknown(name.to_owned())),
+                    }
+                }
+            }
+        }
+        flag
+    }
+
+    fn player_flag(&mut self, object: &Map<String, Value>, key: &str, path: &str) -> PlayerFlag {
+        self.flags(
+            object,
+            key,
+            path,
+            PlayerFlag::from_name,
+            LevelErrorKind::UnknownFlag,
+        )
+    }
+
+    pub(super) fn player_stat(&mut self, value: &Value, path: &str) -> PlayerStat {
+        let object = match self.object(value, path) {
+            Some(object) => object,
+            None => return PlayerStat::default(),
+        };
+        let key_names = self.keys.clone();
+        let fields: Vec<&str> = STAT_FIELDS
+            .iter()
+            .copied()
+            .chain(key_names.iter().map(String::as_str))
+            .collect();
+        self.check_keys(object, &fields, path);
+        let equip = EquipStat {
+            flag: self.player_flag(object, "equip_flag", path),
+            atk: self.get_int(object, "equip_atk", path),
+            def: self.get_int(object, "equip_def", path),
+        };
+        let combat = PlayerCombat {
+            flag: self.player_flag(object, "flag", path),
+            atk: self.get_int(object, "atk", path),
+            def: self.get_int(object, "def", path),
+            equip,
+            ..Default::default()
+        };
+        let mut stat = PlayerStat {
+            hp: self.get_int(object, "hp", path),
+            combat,
+            gr: self.get_int(object, "gr", path),
+            rep: self.get_int(object, "rep", path),
+            ..Default::default()
+        };
+        for (key, name) in stat.keys.iter_mut().zip(&key_names) {
+            *key = self.get_int(object, name, path);
+        }
+        stat
+    }
+
+    // Score function given by preset name, or by weights of each stat
+    fn score(&mut self, value: &Value, path: &str) -> ScoreFunction {
+        if let Some(name) = value.as_str() {
+            return ScoreFunction::from_name(name).unwrap_or_else(|| {
+                self.error(path, LevelErrorKind::UnknownScore(name.to_owned()));
+                ScoreFunction::drod()
+            });
+        }
+        let object = match self.object(value, path) {
+            Some(object) => object,
+            None => return ScoreFunction::drod(),
+        };
+        let key_names = self.keys.clone();
+        let fields: Vec<&str> = ["hp", "atk", "def", "equip_atk", "equip_def", "gr", "rep"]
+            .iter()
+            .copied()
+            .chain(key_names.iter().map(String::as_str))
+            .chain(Some("divisor"))
+            .collect();
+        self.check_keys(object, &fields, path);
+        let mut score = ScoreFunction {
+            hp: self.get_int(object, "hp", path),
+            atk: self.get_int(object, "atk", path),
+            def: self.get_int(object, "def", path),
+            equip_atk: self.get_int(object, "equip_atk", path),
+            equip_def: self.get_int(object, "equip_def", path),
+            gr: self.get_int(object, "gr", path),
+            rep: self.get_int(object, "rep", path),
+            divisor: 1,
+            ..Default::default()
+        };
+        for (weight, name) in score.keys.iter_mut().zip(&key_names) {
+            *weight = self.get_int(object, name, path);
+        }
+        if let Some(value) = object.get("divisor") {
+            let path = format!("{}.divisor", path);
+            let divisor: i32 = self.int(value, &path);
+            if divisor > 0 {
+                score.divisor = divisor;
+            } else if value.is_i64() {
+                self.error(&path, LevelErrorKind::OutOfRange(divisor as i64));
+            }
+        }
+        score
+    }
+
+    fn named_monster(&mut self, value: &Value, path: &str) -> Option<MonsterStat> {
+        let name = self.str(value, path)?;
+        let monster = self.catalog.monster(name).cloned();
+        if monster.is_none() {
+            self.error(path, LevelErrorKind::UnknownMonster(name.to_owned()));
+        }
+        monster
+    }
+
+    // Monster given by catalog name, or by stats optionally based on a catalog entry.
+    // Flags are added to those of the catalog entry while other stats replace them.
+    pub(super) fn monster(&mut self, value: &Value, path: &str) -> Option<MonsterStat> {
+        if value.is_string() {
+            return self.named_monster(value, path);
+        }
+        let object = self.object(value, path)?;
+        self.check_keys(
+            object,
+            &[
+                "name",
+                "flag",
+                "hp",
+                "atk",
+                "def",
+                "gr",
+                "rep",
+                "drain_atk",
+                "drain_def",
+                "pierce",
+                "regen",
+                "immune",
+                "require",
+            ],
+            path,
+        );
+        let mut monster = match object.get("name") {
+            Some(name) => self.named_monster(name, &format!("{}.name", path))?,
+            None => MonsterStat::default(),
+        };
+        monster.flag |= self.flags(
+            object,
+            "flag",
+            path,
+            MonsterFlag::from_name,
+            LevelErrorKind::UnknownFlag,
+        );
+        self.override_int(object, "hp", path, &mut monster.hp);
+        self.override_int(object, "atk", path, &mut monster.atk);
+        self.override_int(object, "def", path, &mut monster.def);
+        self.override_int(object, "gr", path, &mut monster.gr);
+        self.override_int(object, "rep", path, &mut monster.rep);
+        self.override_int(object, "drain_atk", path, &mut monster.drain_atk);
+        self.override_int(object, "drain_def", path, &mut monster.drain_def);
+        self.override_int(object, "pierce", path, &mut monster.pierce);
+        self.override_int(object, "regen", path, &mut monster.regen);
+        monster.immune |= self.player_flag(object, "immune", path);
+        monster.require |= self.player_flag(object, "require", path);
+
+        // Negative amounts would help the player, and more than all of DEF cannot be ignored
+        let amounts = [
+            ("drain_atk", monster.drain_atk as i64),
+            ("drain_def", monster.drain_def as i64),
+            ("pierce", monster.pierce as i64),
+            ("regen", monster.regen as i64),
+        ];
+        for (key, value) in &amounts {
+            if *value < 0 || *key == "pierce" && *value > 100 {
+                let path = format!("{}.{}", path, key);
+                self.error(&path, LevelErrorKind::OutOfRange(*value));
+            }
+        }
+        Some(monster)
+    }
+
+    fn named_equipment(&mut self, value: &Value, path: &str) -> Option<EquipStat> {
+        let name = self.str(value, path)?;
+        let equip = self.catalog.equipment(name).cloned();
+        if equip.is_none() {
+            self.error(path, LevelErrorKind::UnknownEquipment(name.to_owned()));
+        }
+        equip
+    }
+
+    // Equipment given by catalog name, or by stats optionally based on a catalog entry
+    pub(super) fn equipment(&mut self, value: &Value, path: &str) -> Option<EquipStat> {
+        if value.is_string() {
+            return self.named_equipment(value, path);
+        }
+        let object = self.object(value, path)?;
+        self.check_keys(object, &["name", "flag", "atk", "def"], path);
+        let mut equip = match object.get("name") {
+            Some(name) => self.named_equipment(name, &format!("{}.name", path))?,
+            None => EquipStat::default(),
+        };
+        equip.flag |= self.player_flag(object, "flag", path);
+        self.override_int(object, "atk", path, &mut equip.atk);
+        self.override_int(object, "def", path, &mut equip.def);
+        Some(equip)
+    }
+
+    // Add named monsters and equipment to the catalog used by later elements
+    pub(super) fn catalog(&mut self, value: &Value, path: &str) {
+        let object = match self.object(value, path) {
+            Some(object) => object,
+            None => return,
+        };
+        self.check_keys(object, &["monsters", "equipment"], path);
+        if let Some(monsters) = object.get("monsters") {
+            let path = format!("{}.monsters", path);
+            if let Some(monsters) = self.object(monsters, &path) {
+                for (name, monster) in monsters {
+                    if let Some(monster) = self.monster(monster, &format!("{}.{}", path, name)) {
+                        self.catalog.insert_monster(name.clone(), monster);
+                    }
+                }
+            }
+        }
+        if let Some(equipment) = object.get("equipment") {
+            let path = format!("{}.equipment", path);
+            if let Some(equipment) = self.object(equipment, &path) {
+                for (name, equip) in equipment {
+                    if let Some(equip) = self.equipment(equip, &format!("{}.{}", path, name)) {
+                        self.catalog.insert_equipment(name.clone(), equip);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(super) fn into_catalog(self) -> Result<Catalog, LevelErrors> {
+        if self.errors.is_empty() {
+            Ok(self.catalog)
+        } else {
+            Err(LevelErrors(self.errors))
+        }
+    }
+
+    fn inventory(&mut self, value: &Value, path: &str) -> Option<Element> {
+        let object = self.object(value, path)?;
+        self.check_keys(object, &["equip", "weapon", "shield", "accessory"], path);
+        Some(Element::Inventory {
+            equip: self.get_bool(object, "equip", path),
+            weapon: self.get_bool(object, "weapon", path),
+            shield: self.get_bool(object, "shield", path),
+            accessory: self.get_bool(object, "accessory", path),
+        })
+    }
+
+    // Multipliers are percentages of the corresponding player stat
+    fn hp_boost(&mut self, value: &Value, path: &str) -> Option<HpBoostStat> {
+        let object = self.object(value, path)?;
+        self.check_keys(object, &["atk", "def", "equip_atk", "equip_def"], path);
+        Some(HpBoostStat::new(
+            self.get_int(object, "atk", path),
+            self.get_int(object, "def", path),
+            self.get_int(object, "equip_atk", path),
+            self.get_int(object, "equip_def", path),
+        ))
+    }
+
+    // Percentage of current HP gained, or lost when negative. Losing more than all of it is
+    // not allowed, as more HP would then leave less.
+    fn hp_percent(&mut self, value: &Value, path: &str) -> Option<Element> {
+        let percent: i32 = self.int(value, path);
+        if !(-100..=MAX_HP_PERCENT).contains(&percent) {
+            self.error(path, LevelErrorKind::OutOfRange(percent as i64));
+            return None;
+        }
+        Some(Element::HpPercent(percent))
+    }
+
+    // HP set to a percentage of a maximum, rounded down
+    fn hp_fraction(&mut self, value: &Value, path: &str) -> Option<Element> {
+        let object = self.object(value, path)?;
+        self.check_keys(object, &["percent", "max"], path);
+        let percent: i32 = self.get_int(object, "percent", path);
+        let max: i32 = self.get_int(object, "max", path);
+        for (key, value) in &[("percent", percent), ("max", max)] {
+            if *value < 0 {
+                let path = format!("{}.{}", path, key);
+                self.error(&path, LevelErrorKind::OutOfRange(*value as i64));
+            }
+        }
+        let hp = (max as i64 * percent as i64 / 100).min(i32::MAX as i64) as i32;
+        Some(Element::HpSet(hp))
+    }
+
+    // Define counters by name along with their initial values
+    fn counters(&mut self, value: &Value, path: &str) {
+        if let Some(object) = self.object(value, path) {
+            for (name, value) in object {
+                let value = self.int(value, &format!("{}.{}", path, name));
+                self.counters.push((name.clone(), value));
+            }
+        }
+    }
+
+    // Names of the key types, replacing the default ones
+    fn key_types(&mut self, value: &Value, path: &str) {
+        let mut keys = Vec::new();
+        for (i, name) in self.array(value, path).iter().enumerate() {
+            if let Some(name) = self.str(name, &format!("{}[{}]", path, i)) {
+                keys.push(name.to_owned());
+            }
+        }
+        self.keys = keys;
+    }
+
+    // Index of a counter referred to by name
+    fn counter(&mut self, object: &Map<String, Value>, path: &str) -> Option<usize> {
+        let name = self.get(object, "name", path)?;
+        let path = format!("{}.name", path);
+        let name = self.str(name, &path)?;
+        let index = self.counters.iter().position(|(other, _)| other == name);
+        if index.is_none() {
+            self.error(&path, LevelErrorKind::UnknownCounter(name.to_owned()));
+        }
+        index
+    }
+
+    // Counter set to a value or changed by it, saturating at the range of a counter
+    fn counter_change(
+        &mut self,
+        value: &Value,
+        path: &str,
+        element: fn(usize, i8) -> Element,
+    ) -> Option<Element> {
+        let object = self.object(value, path)?;
+        self.check_keys(object, &["name", "value"], path);
+        let value = self.get_int(object, "value", path);
+        Some(element(self.counter(object, path)?, value))
+    }
+
+    // Counter in an inclusive range, which is unbounded on a missing side
+    fn require_counter(&mut self, value: &Value, path: &str) -> Option<Element> {
+        let object = self.object(value, path)?;
+        self.check_keys(object, &["name", "min", "max"], path);
+        let mut min = i8::MIN;
+        let mut max = i8::MAX;
+        self.override_int(object, "min", path, &mut min);
+        self.override_int(object, "max", path, &mut max);
+        Some(Element::RequireCounter(
+            self.counter(object, path)?,
+            min,
+            max,
+        ))
+    }
+
+    // Branches are named like rooms and default to their index
+    fn choice_element(&mut self, value: &Value, path: &str) -> Option<Element> {
+        let mut branches = Vec::new();
+        for (i, branch) in self.array(value, path).iter().enumerate() {
+            let path = format!("{}[{}]", path, i);
+            let branch = match self.object(branch, &path) {
+                Some(branch) => branch,
+                None => continue,
+            };
+            self.check_keys(branch, &["name", "content"], &path);
+            let name = match branch.get("name") {
+                Some(value) => self
+                    .str(value, &format!("{}.name", path))
+                    .unwrap_or_default()
+                    .to_owned(),
+                None => i.to_string(),
+            };
+            let content = self.content(branch, &path);
+            branches.push(Branch { name, content });
+        }
+        if branches.is_empty() {
+            self.error(path, LevelErrorKind::NoBranches);
+            return None;
+        }
+        Some(Element::Choice(branches))
+    }
+
+    // Shop selling goods once for each price, with the name used in the trace
+    fn shop(&mut self, value: &Value, path: &str) -> Option<Element> {
+        let object = self.object(value, path)?;
+        self.check_keys(object, &["name", "goods", "prices"], path);
+        let name = match object.get("name") {
+            Some(value) => self.str(value, &format!("{}.name", path))?,
+            None => "",
+        };
+        let goods = self.get(object, "goods", path)?;
+        let goods = self.player_stat(goods, &format!("{}.goods", path));
+        let value = self.get(object, "prices", path)?;
+        let path = format!("{}.prices", path);
+        let mut prices = Vec::new();
+        for (i, value) in self.array(value, &path).iter().enumerate() {
+            let path = format!("{}[{}]", path, i);
+            let price: i16 = self.int(value, &path);
+            if price < 0 {
+                self.error(&path, LevelErrorKind::OutOfRange(price as i64));
+            }
+            prices.push(price);
+        }
+        Some(Element::shop(name, goods, &prices))
+    }
+
+    // Define named lists of elements. Every template is parsed here, whether it is used or not,
+    // so that its problems are reported once with paths into the definition.
+    fn templates(&mut self, value: &Value, path: &str) {
+        let object = match self.object(value, path) {
+            Some(object) => object,
+            None => return,
+        };
+        for (name, value) in object {
+            self.templates.insert(name.clone(), value.clone());
+        }
+        for name in object.keys() {
+            self.template(name, &format!("{}.{}", path, name));
+        }
+    }
+
+    // Elements of a template, parsing it and the templates it uses on first use
+    fn template(&mut self, name: &str, path: &str) -> Vec<Element> {
+        if let Some(elements) = self.expanded.get(name) {
+            return elements.clone();
+        }
+        if self.expanding.iter().any(|other| other == name) {
+            self.error(path, LevelErrorKind::RecursiveTemplate(name.to_owned()));
+            return Vec::new();
+        }
+        let value = match self.templates.get(name) {
+            Some(value) => value.clone(),
+            None => {
+                self.error(path, LevelErrorKind::UnknownTemplate(name.to_owned()));
+                return Vec::new();
+            }
+        };
+        self.expanding.push(name.to_owned());
+        let elements = self.elements(&value, &format!("$.templates.{}", name));
+        self.expanding.pop();
+        self.expanded.insert(name.to_owned(), elements.clone());
+        elements
+    }
+
+    // Each element is an object with a single key naming its kind.
+    // A template element is replaced by the elements of the template.
+    fn element(&mut self, value: &Value, path: &str, elements: &mut Vec<Element>) {
+        let object = match self.object(value, path) {
+            Some(object) => object,
+            None => return,
+        };
+        let (kind, body) = match object.iter().next() {
+            Some(entry) if object.len() == 1 => entry,
+            _ => {
+                self.error(path, LevelErrorKind::AmbiguousElement);
+                return;
+            }
+        };
+        let path = format!("{}.{}", path, kind);
+        if kind == "template" {
+            if let Some(name) = self.str(body, &path) {
+                elements.extend(self.template(name, &path));
+            }
+            return;
+        }
+        let element = match kind.as_str() {
+            "resource" => Some(Element::Resource(self.player_stat(body, &path))),
+            "cost" => Some(Element::Cost(self.player_stat(body, &path))),
+            "requirement" => Some(Element::Requirement(self.player_stat(body, &path))),
+            "monster" => self.monster(body, &path).map(Element::Monster),
+            "equipment" => self.equipment(body, &path).map(Element::Equipment),
+            "inventory" => self.inventory(body, &path),
+            "hp_boost" => self.hp_boost(body, &path).map(Element::HpBoost),
+            "hp_percent" => self.hp_percent(body, &path),
+            "hp_fraction" => self.hp_fraction(body, &path),
+            "set_counter" => self.counter_change(body, &path, Element::SetCounter),
+            "add_counter" => self.counter_change(body, &path, Element::AddCounter),
+            "require_counter" => self.require_counter(body, &path),
+            "choice" => self.choice_element(body, &path),
+            "shop" => self.shop(body, &path),
+            _ => {
+                self.error(&path, LevelErrorKind::UnknownElement(kind.clone()));
+                None
+            }
+        };
+        elements.extend(element);
+    }
+
+    fn elements(&mut self, value: &Value, path: &str) -> Vec<Element> {
+        let mut elements = Vec::new();
+        for (i, element) in self.array(value, path).iter().enumerate() {
+            self.element(element, &format!("{}[{}]", path, i), &mut elements);
+        }
+        elements
+    }
+
+    fn room(&mut self, value: &Value, path: &str) -> Option<Room> {
+        let object = self.object(value, path)?;
+        self.check_keys(object, &["name", "type", "content"], path);
+        let name = self.get(object, "name", path)?;
+        let name = self.str(name, &format!("{}.name", path))?;
+        let mut room = Room::new(name.to_owned());
+        room.room_type = self.flags(
+            object,
+            "type",
+            path,
+            RoomType::from_name,
+            LevelErrorKind::UnknownRoomType,
+        );
+        room.content = self.content(object, path);
+        Some(room)
+    }
+
+    fn content(&mut self, object: &Map<String, Value>, path: &str) -> Vec<Element> {
+        match object.get("content") {
+            Some(value) => self.elements(value, &format!("{}.content", path)),
+            None => Vec::new(),
+        }
+    }
+
+    fn arcs(&mut self, object: &Map<String, Value>, key: &str, path: &str) -> Vec<Connection> {
+        let mut arcs = Vec::new();
+        if let Some(value) = object.get(key) {
+            let path = format!("{}.{}", path, key);
+            for (i, arc) in self.array(value, &path).iter().enumerate() {
+                let path = format!("{}[{}]", path, i);
+                let pair = match arc.as_array() {
+                    Some(pair) if pair.len() == 2 => pair,
+                    _ => {
+                        self.error(&path, LevelErrorKind::ExpectedType("a pair of room names"));
+                        continue;
+                    }
+                };
+                let from = self.str(&pair[0], &format!("{}[0]", path));
+                let to = self.str(&pair[1], &format!("{}[1]", path));
+                if let (Some(from), Some(to)) = (from, to) {
+                    arcs.push(Connection {
+                        from: from.to_owned(),
+                        to: to.to_owned(),
+                        path,
+                    });
+                }
+            }
+        }
+        arcs
+    }
+
+    // Rooms and connections defined in the same object
+    fn level_part(&mut self, object: &Map<String, Value>, path: &str) -> LevelPart {
+        let mut part = LevelPart::default();
+        if let Some(value) = object.get("rooms") {
+            let path = format!("{}.rooms", path);
+            for (i, room) in self.array(value, &path).iter().enumerate() {
+                let path = format!("{}[{}]", path, i);
+                if let Some(room) = self.room(room, &path) {
+                    part.rooms.push((format!("{}.name", path), room));
+                }
+            }
+        }
+        part.arcs = self.arcs(object, "arcs", path);
+        part.edges = self.arcs(object, "edges", path);
+        part.toggles = self.arcs(object, "toggles", path);
+        part
+    }
+
+    // Mutually exclusive variants of a level
+    fn choice(&mut self, value: &Value, path: &str) -> Option<Choice> {
+        let object = self.object(value, path)?;
+        self.check_keys(object, &["name", "options"], path);
+        let name = self.get(object, "name", path)?;
+        let name = self.str(name, &format!("{}.name", path))?.to_owned();
+        let mut options = Vec::new();
+        if let Some(value) = self.get(object, "options", path) {
+            let path = format!("{}.options", path);
+            for (i, option) in self.array(value, &path).iter().enumerate() {
+                let path = format!("{}[{}]", path, i);
+                let option = match self.object(option, &path) {
+                    Some(option) => option,
+                    None => continue,
+                };
+                self.check_keys(
+                    option,
+                    &["name", "rooms", "arcs", "edges", "toggles"],
+                    &path,
+                );
+                let option_name = match option.get("name") {
+                    Some(value) => self
+                        .str(value, &format!("{}.name", path))
+                        .unwrap_or_default()
+                        .to_owned(),
+                    None => i.to_string(),
+                };
+                options.push((option_name, self.level_part(option, &path)));
+            }
+        }
+        if options.is_empty() {
+            self.error(path, LevelErrorKind::NoOptions);
+            return None;
+        }
+        Some(Choice { name, options })
+    }
+
+    // Check that connections only refer to rooms in the level
+    fn check_arcs(&mut self, arcs: &[Connection], names: &HashSet<&str>) {
+        for arc in arcs {
+            for (i, name) in [&arc.from, &arc.to].iter().enumerate() {
+                if !names.contains(name.as_str()) {
+                    self.error(
+                        &format!("{}[{}]", arc.path, i),
+                        LevelErrorKind::UnknownRoom((*name).clone()),
+                    );
+                }
+            }
+        }
+    }
+
+    pub(super) fn finish<T>(self, value: T) -> Result<T, LevelErrors> {
+        if self.errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(LevelErrors(self.errors))
+        }
+    }
+}
+
+// Name of a weapon or accessory that is not in the catalog, made from its stats
+fn item_name(equip: &EquipStat) -> String {
+    let flags = equip.flag.names().join(" ");
+    if equip.atk == 0 {
+        flags
+    } else if flags.is_empty() {
+        format!("atk {}", equip.atk)
+    } else {
+        format!("atk {} {}", equip.atk, flags)
+    }
+}
+
+// Name of a monster that is not in the catalog, made from its stats
+fn monster_name(monster: &MonsterStat) -> String {
+    format!("hp {} atk {} def {}", monster.hp, monster.atk, monster.def)
+}
+
+// FNV-1a hash, which unlike the default hasher is the same in every build,
+// so that a checkpoint can tell whether it was saved for the same level
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+// Connection between two rooms and where it is defined in the level file
+#[derive(Clone)]
+struct Connection {
+    from: String,
+    to: String,
+    path: String,
+}
+
+// Rooms and connections defined together, along with the paths of the room names
+#[derive(Clone, Default)]
+struct LevelPart {
+    rooms: Vec<(String, Room)>,
+    arcs: Vec<Connection>,
+    edges: Vec<Connection>,
+    toggles: Vec<Connection>,
+}
+
+impl LevelPart {
+    fn add_arcs_to<const N: usize>(&self, level: &mut Level<N>, parser: &mut Parser) {
+        let arcs = self.arcs.iter().chain(&self.edges);
+        let reversed_edges = self.edges.iter().map(|edge| (&edge.to, &edge.from, edge));
+        for (from, to, arc) in arcs
+            .map(|arc| (&arc.from, &arc.to, arc))
+            .chain(reversed_edges)
+        {
+            if let Err(err) = level.select_name(from).and_then(|level| level.add_name(to)) {
+                parser.build_error(&arc.path, err);
+            }
+        }
+        for toggle in &self.toggles {
+            if let Err(err) = level.toggle_name(&toggle.from, &toggle.to) {
+                parser.build_error(&toggle.path, err);
+            }
+        }
+    }
+}
+
+// Named choice between options that each add rooms and connections to the level
+#[derive(Clone)]
+struct Choice {
+    name: String,
+    options: Vec<(String, LevelPart)>,
+}
+
+// Level file parsed into rooms and the connections between them.
+// Each config picks one option of every choice.
+pub struct LevelInfo {
+    pub(super) max_config_number: i32,
+    pub(super) room_count: usize,
+    init_player: PlayerStat,
+    counters: Vec<(String, i8)>,
+    keys: Vec<String>,
+    items: Vec<(String, EquipStat)>, // Weapons and accessories with their names
+    monsters: Vec<(String, MonsterStat)>, // Distinct monsters with their names
+    score: ScoreFunction,
+    base: LevelPart,
+    choices: Vec<Choice>,
+    entrance: String,
+    exit: String,
+    pub(super) fingerprint: u64, // Hash of the level file and the catalog, or 0 when built in code
+}
+
+impl LevelInfo {
+    // Parse level that may refer to monsters and equipment in the given catalog
+    pub fn new(data: Value, catalog: &Catalog) -> Result<Self, LevelErrors> {
+        let mut parser = Parser::new(catalog.clone());
+        let empty = Map::new();
+        let object = parser.object(&data, "$").unwrap_or(&empty);
+        parser.check_keys(
+            object,
+            &[
+                "catalog",
+                "player",
+                "counters",
+                "keys",
+                "templates",
+                "score",
+                "rooms",
+                "arcs",
+                "edges",
+                "toggles",
+                "configs",
+                "entrance",
+                "exit",
+            ],
+            "$",
+        );
+
+        // Names defined by the level are visible to all of its rooms
+        if let Some(value) = object.get("catalog") {
+            parser.catalog(value, "$.catalog");
+        }
+        if let Some(value) = object.get("counters") {
+            parser.counters(value, "$.counters");
+        }
+        if let Some(value) = object.get("keys") {
+            parser.key_types(value, "$.keys");
+        }
+        if let Some(value) = object.get("templates") {
+            parser.templates(value, "$.templates");
+        }
+
+        // HP is shifted by 1 in PlayerStat
+        let mut init_player = match parser.get(object, "player", "$") {
+            Some(player) => parser.player_stat(player, "$.player"),
+            None => PlayerStat::default(),
+        };
+        init_player.hp -= 1;
+
+        let score = match object.get("score") {
+            Some(value) => parser.score(value, "$.score"),
+            None => ScoreFunction::drod(),
+        };
+
+        if !object.contains_key("rooms") {
+            parser.error("$", LevelErrorKind::MissingField("rooms".to_owned()));
+        }
+        let base = parser.level_part(object, "$");
+
+        let mut choices = Vec::new();
+        if let Some(value) = object.get("configs") {
+            for (i, choice) in parser.array(value, "$.configs").iter().enumerate() {
+                if let Some(choice) = parser.choice(choice, &format!("$.configs[{}]", i)) {
+                    choices.push(choice);
+                }
+            }
+        }
+
+        // Missing names are reported by get and str, so only undefined rooms are checked later
+        let entrance = parser
+            .get(object, "entrance", "$")
+            .and_then(|value| parser.str(value, "$.entrance"))
+            .map(str::to_owned);
+        let exit = parser
+            .get(object, "exit", "$")
+            .and_then(|value| parser.str(value, "$.exit"))
+            .map(str::to_owned);
+        let mut level_info =
+            Self::from_parts(parser, init_player, score, base, choices, entrance, exit)?;
+        let mut hasher = StableHasher::new();
+        data.to_string().hash(&mut hasher);
+        catalog.hash(&mut hasher);
+        level_info.fingerprint = hasher.finish();
+        Ok(level_info)
+    }
+
+    // Check the parts of a level together, including the problems of configs that
+    // only occur when some options are picked together
+    fn from_parts(
+        mut parser: Parser,
+        init_player: PlayerStat,
+        score: ScoreFunction,
+        base: LevelPart,
+        choices: Vec<Choice>,
+        entrance: Option<String>,
+        exit: Option<String>,
+    ) -> Result<Self, LevelErrors> {
+        let mut max_config_number: i32 = 1;
+        for choice in &choices {
+            max_config_number = max_config_number
+                .checked_mul(choice.options.len() as i32)
+                .unwrap_or_else(|| {
+                    parser.error("$.configs", LevelErrorKind::TooManyConfigs);
+                    1
+                });
+        }
+
+        // Options of the same choice may define the same room differently,
+        // but no option may redefine a room of the base or of another choice,
+        // as some config picks both
+        let mut names = HashSet::new();
+        for (path, room) in &base.rooms {
+            if !names.insert(room.name.as_str()) {
+                parser.error(path, LevelErrorKind::DuplicateRoom(room.name.clone()));
+            }
+        }
+        let base_names = names.clone();
+        // Rooms of every config, which are those of the base and those defined
+        // by every option of a choice
+        let mut shared_names = base_names.clone();
+        let mut owners: HashMap<&str, usize> = HashMap::new();
+        for (i, choice) in choices.iter().enumerate() {
+            let mut option_counts: HashMap<&str, usize> = HashMap::new();
+            for (_, option) in &choice.options {
+                let mut option_names = HashSet::new();
+                for (path, room) in &option.rooms {
+                    let name = room.name.as_str();
+                    if base_names.contains(name)
+                        || !option_names.insert(name)
+                        || *owners.entry(name).or_insert(i) != i
+                    {
+                        parser.error(path, LevelErrorKind::DuplicateRoom(room.name.clone()));
+                    }
+                    names.insert(name);
+                }
+                for name in option_names {
+                    *option_counts.entry(name).or_default() += 1;
+                }
+            }
+            shared_names.extend(
+                option_counts
+                    .into_iter()
+                    .filter(|&(_, count)| count == choice.options.len())
+                    .map(|(name, _)| name),
+            );
+        }
+
+        let room_count = names.len();
+        if room_count > MAX_ROOM_COUNT {
+            parser.error("$.rooms", LevelErrorKind::TooManyRooms(room_count));
+        }
+        let counters = mem::take(&mut parser.counters);
+        if counters.len() > MAX_COUNTER_COUNT {
+            parser.error(
+                "$.counters",
+                LevelErrorKind::TooManyCounters(counters.len()),
+            );
+        }
+        let keys = mem::take(&mut parser.keys);
+        if keys.len() > MAX_KEY_COUNT {
+            parser.error("$.keys", LevelErrorKind::TooManyKeys(keys.len()));
+        }
+        for (i, name) in keys.iter().enumerate() {
+            if STAT_FIELDS.contains(&name.as_str()) || keys[..i].contains(name) {
+                let path = format!("$.keys[{}]", i);
+                parser.error(&path, LevelErrorKind::DuplicateKey(name.clone()));
+            }
+        }
+
+        let parts = choices
+            .iter()
+            .flat_map(|choice| choice.options.iter().map(|(_, option)| option));
+        let mut items: Vec<(String, EquipStat)> = Vec::new();
+        let mut monsters: Vec<(String, MonsterStat)> = Vec::new();
+        for part in Some(&base).into_iter().chain(parts) {
+            // Connections may refer to rooms of every config and to those of their part
+            let mut part_names = shared_names.clone();
+            part_names.extend(part.rooms.iter().map(|(_, room)| room.name.as_str()));
+            parser.check_arcs(&part.arcs, &part_names);
+            parser.check_arcs(&part.edges, &part_names);
+            parser.check_arcs(&part.toggles, &part_names);
+
+            for (path, room) in &part.rooms {
+                let count = room.choice_count();
+                if count > MAX_CHOICE_COUNT {
+                    parser.error(path, LevelErrorKind::TooManyChoices(count));
+                }
+
+                // Only rooms added in code may refer to counters by an invalid index
+                for element in room.elements() {
+                    match element.counter() {
+                        Some(index) if index >= counters.len() => {
+                            parser.error(path, LevelErrorKind::UnknownCounter(index.to_string()))
+                        }
+                        _ => {}
+                    }
+
+                    // Weapons and accessories are kept in the inventory, and named after
+                    // the catalog entry with the same stats when there is one
+                    if let Element::Equipment(equip) = element {
+                        if (equip.is_weapon() || equip.is_accessory())
+                            && !items.iter().any(|(_, item)| item == equip)
+                        {
+                            let name = match parser.catalog.equipment_name(equip) {
+                                Some(name) => name.to_owned(),
+                                None => item_name(equip),
+                            };
+                            items.push((name, equip.clone()));
+                        }
+                    }
+
+                    if let Element::Monster(monster) = element {
+                        if !monsters.iter().any(|(_, other)| other == monster) {
+                            let name = match parser.catalog.monster_name(monster) {
+                                Some(name) => name.to_owned(),
+                                None => monster_name(monster),
+                            };
+                            monsters.push((name, monster.clone()));
+                        }
+                    }
+                }
+            }
+        }
+
+        if items.len() > MAX_ITEM_COUNT {
+            parser.error("$.rooms", LevelErrorKind::TooManyItems(items.len()));
+        }
+
+        if let Some(name) = &entrance {
+            if !shared_names.contains(name.as_str()) {
+                parser.error("$.entrance", LevelErrorKind::MissingEntrance);
+            }
+        }
+        if let Some(name) = &exit {
+            if !shared_names.contains(name.as_str()) {
+                parser.error("$.exit", LevelErrorKind::MissingExit);
+            }
+        }
+
+        parser.finish(Self {
+            max_config_number,
+            room_count,
+            init_player,
+            counters,
+            keys,
+            items,
+            monsters,
+            score,
+            base,
+            choices,
+            entrance: entrance.unwrap_or_default(),
+            exit: exit.unwrap_or_default(),
+            fingerprint: 0,
+        })
+    }
+
+    pub(super) fn init_player(&self) -> PlayerStat {
+        self.init_player.clone()
+    }
+
+    // Every distinct monster of the level in order of appearance, with its name
+    pub fn monsters(&self) -> &[(String, MonsterStat)] {
+        &self.monsters
+    }
+
+    pub(super) fn init_counters(&self) -> Counters {
+        let mut counters = Counters::default();
+        for (counter, (_, value)) in counters.iter_mut().zip(&self.counters) {
+            *counter = *value;
+        }
+        counters
+    }
+
+    // Names of the key types in the order of PlayerStat::keys
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    // Number of configs, which are numbered from 0
+    pub fn config_count(&self) -> i32 {
+        self.max_config_number
+    }
+
+    // Name of each choice and the option picked for it by the config
+    pub fn config_options(&self, config: i32) -> Vec<(&str, &str)> {
+        self.choices
+            .iter()
+            .zip(self.options(config))
+            .map(|(choice, option)| (choice.name.as_str(), choice.options[option].0.as_str()))
+            .collect()
+    }
+
+    // Index of the option picked for each choice, with the first choice changing slowest
+    fn options(&self, config: i32) -> Vec<usize> {
+        let mut config = config as usize;
+        let mut options = vec![0; self.choices.len()];
+        for (choice, option) in self.choices.iter().zip(options.iter_mut()).rev() {
+            *option = config % choice.options.len();
+            config /= choice.options.len();
+        }
+        options
+    }
+
+    fn parts(&self, config: i32) -> Vec<&LevelPart> {
+        let mut parts = vec![&self.base];
+        for (choice, option) in self.choices.iter().zip(self.options(config)) {
+            parts.push(&choice.options[option].1);
+        }
+        parts
+    }
+
+    pub(super) fn build<const N: usize>(&self, config: i32) -> Result<Level<N>, LevelErrors> {
+        let mut parser = Parser::new(Catalog::default());
+        let mut level = Level::new();
+        level.score = self.score.clone();
+        level.keys = self.keys.clone();
+        level.items = self.items.iter().map(|(_, item)| item.clone()).collect();
+        level.item_names = self.items.iter().map(|(name, _)| name.clone()).collect();
+        level.drains = self
+            .monsters
+            .iter()
+            .any(|(_, monster)| monster.drain_atk > 0 || monster.drain_def > 0);
+        let parts = self.parts(config);
+
+        // Connections may refer to rooms from any part
+        for part in &parts {
+            for (path, room) in &part.rooms {
+                if let Err(err) = level.select_room(room.clone()) {
+                    parser.build_error(path, err);
+                }
+            }
+        }
+        for part in &parts {
+            part.add_arcs_to(&mut level, &mut parser);
+        }
+
+        if level.set_entrance_name(&self.entrance).is_err() {
+            parser.error("$.entrance", LevelErrorKind::MissingEntrance);
+        }
+        if level.set_exit_name(&self.exit).is_err() {
+            parser.error("$.exit", LevelErrorKind::MissingExit);
+        }
+        parser.finish(level)
+    }
+
+    // Option picked for each choice by name
+    pub(super) fn config_to_json(&self, config: i32) -> Value {
+        let mut object = Map::new();
+        for (choice, option) in self.choices.iter().zip(self.options(config)) {
+            object.insert(
+                choice.name.clone(),
+                Value::from(choice.options[option].0.clone()),
+            );
+        }
+        Value::from(object)
+    }
+
+    pub(super) fn print_config(&self, writer: &mut dyn Write, config: i32) -> io::Result<()> {
+        for (choice, option) in self.choices.iter().zip(self.options(config)) {
+            writeln!(writer, "{}: {}", choice.name, choice.options[option].0)?;
+        }
+        Ok(())
+    }
+}
+
+// Level put together in code instead of parsed from a level file.
+// Rooms and connections are added to the base of the level, or to the selected option.
+// Problems are reported with the paths they would have in the equivalent level file.
+pub struct LevelBuilder {
+    init_player: PlayerStat,
+    counters: Vec<(String, i8)>,
+    keys: Vec<String>,
+    score: ScoreFunction,
+    base: LevelPart,
+    choices: Vec<Choice>,
+    selected: Option<(usize, usize)>,
+    entrance: Option<String>,
+    exit: Option<String>,
+}
+
+impl LevelBuilder {
+    pub fn new() -> Self {
+        Self {
+            init_player: PlayerStat::default(),
+            counters: Vec::new(),
+            keys: default_keys(),
+            score: ScoreFunction::drod(),
+            base: LevelPart::default(),
+            choices: Vec::new(),
+            selected: None,
+            entrance: None,
+            exit: None,
+        }
+    }
+
+    // HP is not shifted, as in the player of a level file
+    pub fn player(&mut self, stat: PlayerStat) -> &mut Self {
+        self.init_player = stat;
+        self
+    }
+
+    // Define a counter with its initial value.
+    // Elements refer to counters by the order they are defined in.
+    pub fn counter(&mut self, name: &str, value: i8) -> &mut Self {
+        self.counters.push((name.to_owned(), value));
+        self
+    }
+
+    // Replace the key types, which are yk, gk and bk by default.
+    // Stats and the score function refer to key types by the order they are given in.
+    pub fn keys(&mut self, names: &[&str]) -> &mut Self {
+        self.keys = names.iter().map(|&name| name.to_owned()).collect();
+        self
+    }
+
+    pub fn score(&mut self, score: ScoreFunction) -> &mut Self {
+        self.score = score;
+        self
+    }
+
+    // Add the following rooms and connections to the base of the level
+    pub fn select_base(&mut self) -> &mut Self {
+        self.selected = None;
+        self
+    }
+
+    // Add the following rooms and connections to an option of a choice,
+    // creating either of them when it does not exist yet
+    pub fn select_option(&mut self, choice: &str, option: &str) -> &mut Self {
+        let choice_index = match self.choices.iter().position(|other| other.name == choice) {
+            Some(index) => index,
+            None => {
+                self.choices.push(Choice {
+                    name: choice.to_owned(),
+                    options: Vec::new(),
+                });
+                self.choices.len() - 1
+            }
+        };
+        let options = &mut self.choices[choice_index].options;
+        let option_index = match options.iter().position(|(name, _)| name == option) {
+            Some(index) => index,
+            None => {
+                options.push((option.to_owned(), LevelPart::default()));
+                options.len() - 1
+            }
+        };
+        self.selected = Some((choice_index, option_index));
+        self
+    }
+
+    // Selected part and its path in the equivalent level file
+    fn part(&mut self) -> (&mut LevelPart, String) {
+        match self.selected {
+            Some((choice, option)) => (
+                &mut self.choices[choice].options[option].1,
+                format!("$.configs[{}].options[{}]", choice, option),
+            ),
+            None => (&mut self.base, "$".to_owned()),
+        }
+    }
+
+    pub fn room(&mut self, room: Room) -> &mut Self {
+        let (part, path) = self.part();
+        let path = format!("{}.rooms[{}].name", path, part.rooms.len());
+        part.rooms.push((path, room));
+        self
+    }
+
+    fn connection(from: &str, to: &str, path: String, arcs: &mut Vec<Connection>) {
+        let path = format!("{}[{}]", path, arcs.len());
+        arcs.push(Connection {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            path,
+        });
+    }
+
+    pub fn arc(&mut self, from: &str, to: &str) -> &mut Self {
+        let (part, path) = self.part();
+        Self::connection(from, to, path + ".arcs", &mut part.arcs);
+        self
+    }
+
+    pub fn edge(&mut self, from: &str, to: &str) -> &mut Self {
+        let (part, path) = self.part();
+        Self::connection(from, to, path + ".edges", &mut part.edges);
+        self
+    }
+
+    pub fn toggle(&mut self, from: &str, to: &str) -> &mut Self {
+        let (part, path) = self.part();
+        Self::connection(from, to, path + ".toggles", &mut part.toggles);
+        self
+    }
+
+    pub fn entrance(&mut self, name: &str) -> &mut Self {
+        self.entrance = Some(name.to_owned());
+        self
+    }
+
+    pub fn exit(&mut self, name: &str) -> &mut Self {
+        self.exit = Some(name.to_owned());
+        self
+    }
+
+    pub fn build(&self) -> Result<LevelInfo, LevelErrors> {
+        let mut parser = Parser::new(Catalog::default());
+        parser.counters = self.counters.clone();
+        parser.keys = self.keys.clone();
+        for (key, name) in &[("entrance", &self.entrance), ("exit", &self.exit)] {
+            if name.is_none() {
+                parser.error("$", LevelErrorKind::MissingField((*key).to_owned()));
+            }
+        }
+
+        // HP is shifted by 1 in PlayerStat
+        let mut init_player = self.init_player.clone();
+        init_player.hp -= 1;
+
+        LevelInfo::from_parts(
+            parser,
+            init_player,
+            self.score.clone(),
+            self.base.clone(),
+            self.choices.clone(),
+            self.entrance.clone(),
+            self.exit.clone(),
+        )
+    }
+}
+
+impl Default for LevelBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    // Every problem reported for the level, in the order found
+    fn errors(data: Value) -> Vec<String> {
+        match LevelInfo::new(data, &Catalog::builtin()) {
+            Ok(_) => Vec::new(),
+            Err(LevelErrors(errors)) => errors.iter().map(|error| error.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn every_problem_with_its_path() {
+        let errors = errors(json!({
+            "player": {"hp": 100, "atk": 10, "def": 10, "colour": 1},
+            "rooms": [
+                {"name": "S"},
+                {"name": "S", "content": [{"resource": {"hp": "x"}}]},
+                {"name": "E"}
+            ],
+            "arcs": [["S", "Nowhere"], ["S", "E"]],
+            "entrance": "S",
+            "exit": "E"
+        }));
+        assert_eq!(
+            errors,
+            [
+                "$.player.colour: unknown field \"colour\"",
+                "$.rooms[1].content[0].resource.hp: expected an integer",
+                "$.rooms[1].name: room \"S\" is defined more than once",
+                "$.arcs[0][1]: room \"Nowhere\" is not defined",
+            ]
+        );
+    }
+
+    #[test]
+    fn elements_keys_and_ends() {
+        let errors = errors(json!({
+            "player": {"hp": 100},
+            "keys": ["yk", "hp", "yk"],
+            "rooms": [{"name": "S", "content": [
+                {"potion": 5},
+                {"resource": {"hp": 1}, "cost": {"gr": 1}},
+                {"hp_percent": 10001}
+            ]}],
+            "entrance": "A",
+            "exit": "B"
+        }));
+        assert_eq!(
+            errors,
+            [
+                "$.rooms[0].content[0].potion: unknown element kind \"potion\"",
+                "$.rooms[0].content[1]: an element must have exactly one field",
+                "$.rooms[0].content[2].hp_percent: 10001 is out of range",
+                "$.keys[1]: key type \"hp\" is defined more than once or is the name of a stat",
+                "$.keys[2]: key type \"yk\" is defined more than once or is the name of a stat",
+                "$.entrance: the entrance room is not defined",
+                "$.exit: the exit room is not defined",
+            ]
+        );
+    }
+
+    #[test]
+    fn too_many_rooms() {
+        let rooms: Vec<Value> = (0..=MAX_ROOM_COUNT)
+            .map(|i| json!({ "name": format!("R{}", i) }))
+            .collect();
+        let errors = errors(json!({
+            "player": {"hp": 100},
+            "rooms": rooms,
+            "entrance": "R0",
+            "exit": "R1"
+        }));
+        assert_eq!(
+            errors,
+            ["$.rooms: there are 257 rooms but at most 256 are supported"]
+        );
+    }
+
+    // Problems that only occur when some options are picked together
+    #[test]
+    fn problems_of_configs() {
+        let errors = errors(json!({
+            "player": {"hp": 100},
+            "rooms": [{"name": "S"}],
+            "arcs": [["S", "X"], ["S", "Both"]],
+            "configs": [
+                {"name": "A", "options": [
+                    {"name": "a0", "rooms": [{"name": "X"}, {"name": "Both"}], "arcs": [["X", "E"]]},
+                    {"name": "a1", "rooms": [{"name": "Both"}, {"name": "D"}, {"name": "D"}]}
+                ]},
+                {"name": "B", "options": [
+                    {"name": "b0", "rooms": [{"name": "E"}]},
+                    {"name": "b1", "rooms": [{"name": "E"}, {"name": "D"}]}
+                ]}
+            ],
+            "entrance": "S",
+            "exit": "E"
+        }));
+        assert_eq!(
+            errors,
+            [
+                "$.configs[0].options[1].rooms[2].name: room \"D\" is defined more than once",
+                "$.configs[1].options[1].rooms[1].name: room \"D\" is defined more than once",
+                "$.arcs[0][1]: room \"X\" is not defined",
+            ]
+        );
+    }
+
+    // Configs are checked without building each of them
+    #[test]
+    fn many_configs() {
+        let configs: Vec<Value> = (0..30)
+            .map(|i| {
+                let name = format!("R{}", i);
+                json!({"name": name, "options": [
+                    {"name": "in", "rooms": [{"name": name}], "arcs": [["S", name]]},
+                    {"name": "out"}
+                ]})
+            })
+            .collect();
+        let level_info = LevelInfo::new(
+            json!({
+                "player": {"hp": 100},
+                "rooms": [{"name": "S"}, {"name": "E"}],
+                "arcs": [["S", "E"]],
+                "configs": configs,
+                "entrance": "S",
+                "exit": "E"
+            }),
+            &Catalog::builtin(),
+        )
+        .unwrap();
+        assert_eq!(level_info.config_count(), 1 << 30);
+    }
+
+    #[test]
+    fn valid_level() {
+        let errors = errors(json!({
+            "player": {"hp": 100},
+            "rooms": [{"name": "S"}, {"name": "E"}],
+            "arcs": [["S", "E"]],
+            "entrance": "S",
+            "exit": "E"
+        }));
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+}
@@ -1,11 +1,60 @@
+mod assets;
+mod bitset;
+mod fight;
+mod level_info;
 mod model;
 mod search;
+mod sharded_map;
+mod solution;
 
-pub use model::LevelInfo;
-pub use search::Search;
-pub use search::SearchConfig;
+pub use assets::Catalog;
+pub use fight::{write_breakpoints, FightInfo};
+pub use level_info::{LevelBuilder, LevelError, LevelErrorKind, LevelErrors, LevelInfo};
+pub use model::{
+    Branch, Element, EquipStat, FightStat, HpBoostStat, MonsterFlag, MonsterStat, PlayerFlag,
+    PlayerStat, Room, RoomType, ScoreFunction,
+};
+pub use search::{search, solve, verify, Checkpoint, OutputFormat, RouteStep, SearchConfig};
+pub use solution::{ConfigResult, PlayerTrace, SearchCallback, SearchResult, TraceStep};
 
-type VertexIDType = u8;
+type VertexIDType = u16;
+
+// Index of the way the choices of a room are made
+type ChoiceIDType = u8;
+
+// Largest number of ways to make the choices of a room
+const MAX_CHOICE_COUNT: usize = ChoiceIDType::MAX as usize + 1;
+
+// Largest number of rooms a level may have across all of its configs
+const MAX_ROOM_COUNT: usize = 256;
+
+// Largest number of counters a level may have
+const MAX_COUNTER_COUNT: usize = 8;
+
+// Values of the counters of a level, which are part of the progress of a player
+type Counters = [i8; MAX_COUNTER_COUNT];
+
+// Largest number of key types a level may have
+pub const MAX_KEY_COUNT: usize = 8;
+
+// Number of keys of each type of a level, in the order the level defines them
+type Keys = [i8; MAX_KEY_COUNT];
+
+// Key types of a level that does not define its own
+const DEFAULT_KEYS: &[&str] = &["yk", "gk", "bk"];
+
+// Largest percentage of HP an element may add, which multiplies HP by 101
+const MAX_HP_PERCENT: i32 = 10000;
+
+// Largest number of weapons and accessories a level may have, which players keep once picked up
+const MAX_ITEM_COUNT: usize = 32;
+
+// Weapons and accessories a player owns, as a bit for each of those of the level
+type ItemSet = u32;
+
+fn default_keys() -> Vec<String> {
+    DEFAULT_KEYS.iter().map(|&name| name.to_owned()).collect()
+}
 
 trait Ge<Rhs = Self> {
     fn ge(&self, other: &Rhs) -> bool;
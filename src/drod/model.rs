@@ -1,30 +1,67 @@
-use super::{Ge, VertexIDType};
+use super::bitset::BitSet;
+use super::{
+    default_keys, Counters, Ge, ItemSet, Keys, VertexIDType, MAX_CHOICE_COUNT, MAX_KEY_COUNT,
+};
 
 use bitflags::bitflags;
-use rust_dense_bitset::BitSet as _;
-use rust_dense_bitset::DenseBitSet as BitSet;
-use serde_json::Value;
+use serde_json::{json, Value};
 
+use std::array;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use std::io;
-use std::io::Write;
 use std::ops::{AddAssign, Neg, Sub};
-use std::u8;
+use std::slice;
+use std::str::FromStr;
 
 // Character behaviors that affect gameplay
 bitflags! {
     #[derive(Default)]
-    pub(super) struct PlayerFlag: u8 {
-        const DEAD                      = 0b00001;
-        const HAS_WEAPON                = 0b00010;
-        const DOUBLE_GR_WEAPON          = 0b00100;
-        const DOUBLE_ATK_AGAINST_GOBLIN = 0b01000;
-        const DOUBLE_ATK_AGAINST_WYRM   = 0b10000;
+    pub struct PlayerFlag: u8 {
+        const DEAD                      = 0b0000001;
+        const HAS_WEAPON                = 0b0000010;
+        const DOUBLE_GR_WEAPON          = 0b0000100;
+        const DOUBLE_ATK_AGAINST_GOBLIN = 0b0001000;
+        const DOUBLE_ATK_AGAINST_WYRM   = 0b0010000;
+        const DOUBLE_GR_ACCESSORY       = 0b0100000;
+        const DOUBLE_REP_ACCESSORY      = 0b1000000;
+        const HALF_PRICE_ACCESSORY      = 0b10000000;
         const WEAPON_MASK               = Self::HAS_WEAPON.bits | Self::DOUBLE_GR_WEAPON.bits | Self::DOUBLE_ATK_AGAINST_GOBLIN.bits | Self::DOUBLE_ATK_AGAINST_WYRM.bits;
         const SHIELD_MASK               = 0;
-        const ACCESSORY_MASK            = 0;
+        const ACCESSORY_MASK            = Self::DOUBLE_GR_ACCESSORY.bits | Self::DOUBLE_REP_ACCESSORY.bits | Self::HALF_PRICE_ACCESSORY.bits;
+        const DOUBLE_GR_MASK            = Self::DOUBLE_GR_WEAPON.bits | Self::DOUBLE_GR_ACCESSORY.bits;
+    }
+}
+
+impl PlayerFlag {
+    // Names used in level files
+    const NAMES: &'static [(&'static str, Self)] = &[
+        ("DEAD", Self::DEAD),
+        ("HAS_WEAPON", Self::HAS_WEAPON),
+        ("DOUBLE_GR_WEAPON", Self::DOUBLE_GR_WEAPON),
+        ("DOUBLE_ATK_AGAINST_GOBLIN", Self::DOUBLE_ATK_AGAINST_GOBLIN),
+        ("DOUBLE_ATK_AGAINST_WYRM", Self::DOUBLE_ATK_AGAINST_WYRM),
+        ("DOUBLE_GR_ACCESSORY", Self::DOUBLE_GR_ACCESSORY),
+        ("DOUBLE_REP_ACCESSORY", Self::DOUBLE_REP_ACCESSORY),
+        ("HALF_PRICE_ACCESSORY", Self::HALF_PRICE_ACCESSORY),
+    ];
+
+    // Look up flag by the name used in level files
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(flag_name, _)| *flag_name == name)
+            .map(|(_, flag)| *flag)
+    }
+
+    // Names of the enabled flags in the order of NAMES
+    pub(super) fn names(&self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(_, flag)| self.contains(*flag))
+            .map(|(name, _)| *name)
+            .collect()
     }
 }
 
@@ -55,22 +92,52 @@ impl Display for PlayerFlag {
         } else {
             ""
         };
+        let double_gr_accessory = if self.contains(PlayerFlag::DOUBLE_GR_ACCESSORY) {
+            " DOUBLE_GR_ACCESSORY"
+        } else {
+            ""
+        };
+        let double_rep_accessory = if self.contains(PlayerFlag::DOUBLE_REP_ACCESSORY) {
+            " DOUBLE_REP_ACCESSORY"
+        } else {
+            ""
+        };
+        let half_price_accessory = if self.contains(PlayerFlag::HALF_PRICE_ACCESSORY) {
+            " HALF_PRICE_ACCESSORY"
+        } else {
+            ""
+        };
         write!(
             f,
-            "{}{}{}{}{}",
-            dead, has_weapon, double_gr, double_goblin, double_wyrm
+            "{}{}{}{}{}{}{}{}",
+            dead,
+            has_weapon,
+            double_gr,
+            double_goblin,
+            double_wyrm,
+            double_gr_accessory,
+            double_rep_accessory,
+            half_price_accessory
         )
     }
 }
 
 #[derive(Clone, Default, Eq, Hash, PartialEq)]
-struct EquipStat {
-    flag: PlayerFlag,
-    atk: i16,
-    def: i16,
+pub struct EquipStat {
+    pub flag: PlayerFlag,
+    pub atk: i16,
+    pub def: i16,
 }
 
 impl EquipStat {
+    pub(super) fn is_weapon(&self) -> bool {
+        self.atk > 0
+    }
+
+    pub(super) fn is_accessory(&self) -> bool {
+        self.atk == 0 && self.def == 0 && !self.flag.is_empty()
+    }
+
     fn nonnegative(&self) -> bool {
         self.atk >= 0 && self.def >= 0
     }
@@ -136,7 +203,8 @@ pub(super) struct PlayerCombat {
     pub(super) flag: PlayerFlag,
     pub(super) atk: i16,
     pub(super) def: i16,
-    equip: EquipStat,
+    pub(super) equip: EquipStat,
+    pub(super) items: ItemSet, // Owned weapons and accessories of the level
 }
 
 impl PlayerCombat {
@@ -157,6 +225,67 @@ impl PlayerCombat {
         self.atk = self.atk.max(other.atk);
         self.def = self.def.max(other.def);
         self.equip.join(other.equip);
+        self.items |= other.items;
+    }
+
+    // Equip the weapon instead of the current one
+    fn switch_weapon(&mut self, weapon: &EquipStat) {
+        let current = self.equip.flag & PlayerFlag::WEAPON_MASK;
+        self.flag = (self.flag & !current) | weapon.flag;
+        self.atk += weapon.atk - self.equip.atk;
+        self.equip.flag = (self.equip.flag & !current) | weapon.flag;
+        self.equip.atk = weapon.atk;
+    }
+
+    // Equip the accessory instead of the current one
+    fn switch_accessory(&mut self, accessory: &EquipStat) {
+        let current = self.equip.flag & PlayerFlag::ACCESSORY_MASK;
+        self.flag = (self.flag & !current) | accessory.flag;
+        self.equip.flag = (self.equip.flag & !current) | accessory.flag;
+    }
+
+    // Every other way of equipping an owned weapon and accessory of the level,
+    // along with the indices of the items switched to.
+    // Nothing is switched to while the current one is unequipped.
+    fn loadouts(&self, items: &[EquipStat]) -> Vec<(Vec<u8>, Self)> {
+        let weapon = self.equip.flag & PlayerFlag::WEAPON_MASK;
+        let accessory = self.equip.flag & PlayerFlag::ACCESSORY_MASK;
+        let mut weapons = vec![None];
+        let mut accessories = vec![None];
+        for (index, item) in items.iter().enumerate() {
+            if self.items & 1 << index == 0 {
+                continue;
+            }
+            if item.is_weapon() {
+                if self.flag.contains(weapon) && (item.flag != weapon || item.atk != self.equip.atk)
+                {
+                    weapons.push(Some(index));
+                }
+            } else if self.flag.contains(accessory) && item.flag != accessory {
+                accessories.push(Some(index));
+            }
+        }
+
+        let mut loadouts = Vec::new();
+        for &weapon in &weapons {
+            for &accessory in &accessories {
+                if weapon.is_none() && accessory.is_none() {
+                    continue;
+                }
+                let mut combat = self.clone();
+                let mut switched = Vec::new();
+                if let Some(index) = weapon {
+                    combat.switch_weapon(&items[index]);
+                    switched.push(index as u8);
+                }
+                if let Some(index) = accessory {
+                    combat.switch_accessory(&items[index]);
+                    switched.push(index as u8);
+                }
+                loadouts.push((switched, combat));
+            }
+        }
+        loadouts
     }
 }
 
@@ -166,6 +295,7 @@ impl Ge for PlayerCombat {
             && self.atk >= other.atk
             && self.def >= other.def
             && self.equip.ge(&other.equip)
+            && self.items & other.items == other.items
     }
 }
 
@@ -175,6 +305,7 @@ impl AddAssign<&Self> for PlayerCombat {
         self.atk += other.atk;
         self.def += other.def;
         self.equip += &other.equip;
+        self.items ^= other.items;
     }
 }
 
@@ -187,6 +318,7 @@ impl Sub for &PlayerCombat {
             atk: self.atk - other.atk,
             def: self.def - other.def,
             equip: &self.equip - &other.equip,
+            items: self.items ^ other.items,
         }
     }
 }
@@ -200,6 +332,7 @@ impl Neg for PlayerCombat {
             atk: -self.atk,
             def: -self.def,
             equip: -self.equip,
+            items: self.items,
         }
     }
 }
@@ -228,31 +361,103 @@ impl Ge for PlayerObjective {
 // HP is shifted by 1 so that 0 is considered alive.
 // This change makes code cleaner.
 #[derive(Clone, Default)]
-pub(super) struct PlayerStat {
+pub struct PlayerStat {
     pub(super) hp: i32,
-    combat: PlayerCombat,
-    gr: i16,
-    pub(super) yk: i8,
-    pub(super) gk: i8,
-    pub(super) bk: i8,
+    pub(super) combat: PlayerCombat,
+    pub(super) gr: i16,
+    pub(super) rep: i16,
+    pub(super) keys: Keys,
 }
 
 impl PlayerStat {
-    pub(super) fn with_stat(hp: i32, atk: i16, def: i16) -> Self {
+    // Stats with the given values and nothing else, with HP not shifted
+    pub fn new(hp: i32, atk: i16, def: i16) -> Self {
         Self {
-            hp: hp - 1,
+            hp,
             combat: PlayerCombat::with_stat(atk, def),
             ..Default::default()
         }
     }
 
+    pub(super) fn with_stat(hp: i32, atk: i16, def: i16) -> Self {
+        Self::new(hp - 1, atk, def)
+    }
+
+    pub fn with_flag(mut self, flag: PlayerFlag) -> Self {
+        self.combat.flag = flag;
+        self
+    }
+
+    pub fn with_equip(mut self, equip: EquipStat) -> Self {
+        self.combat.equip = equip;
+        self
+    }
+
+    pub fn with_gr(mut self, gr: i16) -> Self {
+        self.gr = gr;
+        self
+    }
+
+    pub fn with_rep(mut self, rep: i16) -> Self {
+        self.rep = rep;
+        self
+    }
+
+    // Keys of each type in the order the level defines them, starting from the first type
+    pub fn with_keys(mut self, keys: &[i8]) -> Self {
+        for (key, &count) in self.keys.iter_mut().zip(keys) {
+            *key = count;
+        }
+        self
+    }
+
+    pub fn hp(&self) -> i32 {
+        self.hp
+    }
+
+    pub fn atk(&self) -> i16 {
+        self.combat.atk
+    }
+
+    pub fn def(&self) -> i16 {
+        self.combat.def
+    }
+
+    pub fn flag(&self) -> PlayerFlag {
+        self.combat.flag
+    }
+
+    pub fn equip(&self) -> &EquipStat {
+        &self.combat.equip
+    }
+
+    pub fn gr(&self) -> i16 {
+        self.gr
+    }
+
+    pub fn rep(&self) -> i16 {
+        self.rep
+    }
+
+    // Keys of each type in the order the level defines them, followed by unused types
+    pub fn keys(&self) -> &[i8] {
+        &self.keys
+    }
+
+    // Stats of a player with HP as written in level files
+    pub(super) fn unshifted(&self) -> Self {
+        Self {
+            hp: self.hp + 1,
+            ..self.clone()
+        }
+    }
+
     pub(super) fn nonnegative(&self) -> bool {
         self.hp >= 0
             && self.combat.nonnegative()
             && self.gr >= 0
-            && self.yk >= 0
-            && self.gk >= 0
-            && self.bk >= 0
+            && self.rep >= 0
+            && self.keys.iter().all(|&key| key >= 0)
     }
 
     // Find max of two player stats
@@ -260,14 +465,42 @@ impl PlayerStat {
         self.hp = self.hp.max(other.hp);
         self.combat.join(other.combat);
         self.gr = self.gr.max(other.gr);
-        self.yk = self.yk.max(other.yk);
-        self.gk = self.gk.max(other.gk);
-        self.bk = self.bk.max(other.bk);
+        self.rep = self.rep.max(other.rep);
+        for (key, other) in self.keys.iter_mut().zip(&other.keys) {
+            *key = (*key).max(*other);
+        }
     }
 
     pub(super) fn objective(&self) -> PlayerObjective {
         PlayerObjective { hp: self.hp }
     }
+
+    // Same fields as the player of a level file, with keys named by type
+    pub(super) fn to_json(&self, key_names: &[String]) -> Value {
+        let mut value = json!({
+            "hp": self.hp,
+            "atk": self.combat.atk,
+            "def": self.combat.def,
+            "flag": self.combat.flag.names(),
+            "equip_atk": self.combat.equip.atk,
+            "equip_def": self.combat.equip.def,
+            "equip_flag": self.combat.equip.flag.names(),
+            "gr": self.gr,
+            "rep": self.rep,
+        });
+        for (name, key) in key_names.iter().zip(&self.keys) {
+            value[name] = json!(key);
+        }
+        value
+    }
+
+    // Stats as shown in traces, with keys named by type
+    pub(super) fn display<'a>(&'a self, key_names: &'a [String]) -> StatDisplay<'a> {
+        StatDisplay {
+            stat: self,
+            key_names,
+        }
+    }
 }
 
 impl AsRef<PlayerCombat> for PlayerStat {
@@ -281,9 +514,12 @@ impl Ge for PlayerStat {
         self.hp >= other.hp
             && self.combat.ge(&other.combat)
             && self.gr >= other.gr
-            && self.yk >= other.yk
-            && self.gk >= other.gk
-            && self.bk >= other.bk
+            && self.rep >= other.rep
+            && self
+                .keys
+                .iter()
+                .zip(&other.keys)
+                .all(|(key, other)| key >= other)
     }
 }
 
@@ -292,9 +528,10 @@ impl AddAssign<&Self> for PlayerStat {
         self.hp += other.hp;
         self.combat += &other.combat;
         self.gr += other.gr;
-        self.yk += other.yk;
-        self.gk += other.gk;
-        self.bk += other.bk;
+        self.rep += other.rep;
+        for (key, other) in self.keys.iter_mut().zip(&other.keys) {
+            *key += other;
+        }
     }
 }
 
@@ -306,9 +543,8 @@ impl Sub for &PlayerStat {
             hp: self.hp - other.hp,
             combat: &self.combat - &other.combat,
             gr: self.gr - other.gr,
-            yk: self.yk - other.yk,
-            gk: self.gk - other.gk,
-            bk: self.bk - other.bk,
+            rep: self.rep - other.rep,
+            keys: array::from_fn(|i| self.keys[i] - other.keys[i]),
         }
     }
 }
@@ -321,42 +557,247 @@ impl Neg for PlayerStat {
             hp: -self.hp,
             combat: -self.combat,
             gr: -self.gr,
-            yk: -self.yk,
-            gk: -self.gk,
-            bk: -self.bk,
+            rep: -self.rep,
+            keys: self.keys.map(|key| -key),
         }
     }
 }
 
-impl Display for PlayerStat {
+// Player stats with the names of the key types of a level, as keys have no names of their own
+pub(super) struct StatDisplay<'a> {
+    stat: &'a PlayerStat,
+    key_names: &'a [String],
+}
+
+impl Display for StatDisplay<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        writeln!(
+        let stat = self.stat;
+        write!(
             f,
-            "hp: {}, flag: {}, atk: {}, def: {}, {}, gr: {}, yk: {}, gk: {}, bk: {}",
-            self.hp + 1,
-            self.combat.flag,
-            self.combat.atk,
-            self.combat.def,
-            self.combat.equip,
-            self.gr,
-            self.yk,
-            self.gk,
-            self.bk
-        )
+            "hp: {}, flag: {}, atk: {}, def: {}, {}, gr: {}, rep: {}",
+            stat.hp + 1,
+            stat.combat.flag,
+            stat.combat.atk,
+            stat.combat.def,
+            stat.combat.equip,
+            stat.gr,
+            stat.rep
+        )?;
+        for (name, key) in self.key_names.iter().zip(&stat.keys) {
+            write!(f, ", {}: {}", name, key)?;
+        }
+        writeln!(f)
+    }
+}
+
+// Percentage of a value rounded towards negative infinity, saturating at the range of i32
+fn percent_floor<T: Into<i32>>(mult: T, num: T) -> i32 {
+    let prod = mult.into() as i64 * num.into() as i64;
+    prod.div_euclid(100).clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+// Change of HP that depends on the HP before it. Each change never decreases
+// when HP increases, so a player with more HP is still at least as good after it.
+// HP is not shifted here.
+#[derive(Clone, Copy)]
+pub(super) enum HpOp {
+    Add(i32),
+    Percent(i32), // At least -100
+    Set(i32),
+}
+
+impl HpOp {
+    fn apply(&self, hp: i32) -> i32 {
+        match *self {
+            Self::Add(diff) => hp.saturating_add(diff),
+            Self::Percent(percent) => hp.saturating_add(percent_floor(percent, hp)),
+            Self::Set(value) => value,
+        }
+    }
+
+    // Least HP before the change that leaves at least the given HP,
+    // i32::MIN when any HP does and none when no HP does
+    fn preimage(&self, hp: i32) -> Option<i32> {
+        match *self {
+            Self::Add(diff) => Some(hp.saturating_sub(diff)),
+            Self::Percent(percent) if percent > -100 => {
+                let ceil = -((-(hp as i64) * 100).div_euclid(100 + percent as i64));
+                Some(ceil.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+            }
+            Self::Percent(_) | Self::Set(_) => {
+                if self.apply(1) >= hp {
+                    Some(i32::MIN)
+                } else {
+                    None
+                }
+            }
+        }
     }
 }
 
-// Result of completing a room element
+// Change or test of a counter given by its index
+#[derive(Clone, Copy)]
+pub(super) enum CounterOp {
+    Set(usize, i8),
+    Add(usize, i8),
+    Require(usize, i8, i8), // Inclusive range
+}
+
+// Result of completing a room element.
+// HP first goes through hp_ops in order and then changes by the HP of diff.
 #[derive(Clone, Default)]
 pub(super) struct ProbeStat {
     pub(super) diff: PlayerStat,
     pub(super) req: PlayerStat,
+    pub(super) hp_ops: Vec<HpOp>,
+    pub(super) counter_ops: Vec<CounterOp>,
+    pub(super) equips: Vec<u8>, // Items of the level equipped for fights, in order
+    pub(super) drained: [i16; 2], // ATK and DEF drained by monsters
+    pub(super) choice: usize,   // Way of making the choices, in the order of choice_names
+}
+
+impl ProbeStat {
+    fn dead() -> Self {
+        let combat = PlayerCombat {
+            flag: PlayerFlag::DEAD,
+            ..Default::default()
+        };
+        let dead = PlayerStat {
+            combat,
+            ..Default::default()
+        };
+        ProbeStat {
+            diff: dead.clone(),
+            req: dead,
+            ..Default::default()
+        }
+    }
+
+    // Effect requiring enough HP to stay alive afterwards
+    fn hp_op(op: HpOp) -> Self {
+        match op.preimage(1) {
+            Some(hp) => {
+                let req = PlayerStat {
+                    hp: hp.saturating_sub(1).max(0),
+                    ..Default::default()
+                };
+                ProbeStat {
+                    req,
+                    hp_ops: vec![op],
+                    ..Default::default()
+                }
+            }
+            None => Self::dead(),
+        }
+    }
+
+    // Least shifted HP before the HP ops that leaves at least the given shifted HP after them
+    fn hp_preimage(&self, hp: i32) -> Option<i32> {
+        let mut hp = hp.saturating_add(1);
+        for op in self.hp_ops.iter().rev() {
+            hp = op.preimage(hp)?;
+            if hp == i32::MIN {
+                break;
+            }
+        }
+        Some(hp.saturating_sub(1))
+    }
+
+    // Order of fights from the best one. Staying alive comes first, then losing less HP,
+    // then gaining more GR and REP, then losing less ATK and DEF.
+    fn fight_key(&self) -> (bool, i32, i16, i16, i16, i16) {
+        (
+            !self.req.combat.flag.contains(PlayerFlag::DEAD),
+            self.diff.hp,
+            self.diff.gr,
+            self.diff.rep,
+            self.diff.combat.atk,
+            self.diff.combat.def,
+        )
+    }
+
+    // Whether the result leaves the player at least as well off as another one of the same
+    // elements in every stat, so the other one need not be tried
+    fn dominates(&self, other: &Self) -> bool {
+        let (alive, hp, gr, rep, atk, def) = self.fight_key();
+        let (other_alive, other_hp, other_gr, other_rep, other_atk, other_def) = other.fight_key();
+        if !other_alive {
+            return true;
+        }
+        alive
+            && hp >= other_hp
+            && gr >= other_gr
+            && rep >= other_rep
+            && atk >= other_atk
+            && def >= other_def
+            && other.req.ge(&self.req)
+            && self.hp_ops.is_empty()
+            && other.hp_ops.is_empty()
+    }
+
+    fn counter_op(op: CounterOp) -> Self {
+        ProbeStat {
+            counter_ops: vec![op],
+            ..Default::default()
+        }
+    }
+
+    // Counters after the counter ops, or none when one of their requirements is not met
+    pub(super) fn counters(&self, counters: &Counters) -> Option<Counters> {
+        let mut counters = *counters;
+        for op in &self.counter_ops {
+            match *op {
+                CounterOp::Set(index, value) => counters[index] = value,
+                CounterOp::Add(index, value) => {
+                    counters[index] = counters[index].saturating_add(value)
+                }
+                CounterOp::Require(index, min, max) => {
+                    if !(min..=max).contains(&counters[index]) {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(counters)
+    }
+
+    pub(super) fn apply(&self, stat: &mut PlayerStat) {
+        for op in &self.hp_ops {
+            stat.hp = op.apply(stat.hp.saturating_add(1)).saturating_sub(1);
+        }
+        *stat += &self.diff;
+    }
 }
 
 impl AddAssign<&Self> for ProbeStat {
     fn add_assign(&mut self, other: &Self) {
-        self.req.join(&other.req - &self.diff);
-        self.diff += &other.diff;
+        let mut req = &other.req - &self.diff;
+        // Flags and items gained earlier in the room are not needed before entering it
+        req.combat.flag = other.req.combat.flag;
+        req.combat.equip.flag = other.req.combat.equip.flag;
+        req.combat.items = other.req.combat.items;
+        if !self.hp_ops.is_empty() {
+            match self.hp_preimage(other.req.hp - self.diff.hp) {
+                Some(hp) => req.hp = hp.max(0),
+                None => req.combat.flag |= PlayerFlag::DEAD,
+            }
+        }
+        self.req.join(req);
+
+        if other.hp_ops.is_empty() {
+            self.diff += &other.diff;
+        } else {
+            if self.diff.hp != 0 {
+                self.hp_ops.push(HpOp::Add(self.diff.hp));
+            }
+            self.hp_ops.extend_from_slice(&other.hp_ops);
+            self.diff += &other.diff;
+            self.diff.hp = other.diff.hp;
+        }
+        self.counter_ops.extend_from_slice(&other.counter_ops);
+        self.equips.extend_from_slice(&other.equips);
+        self.drained[0] += other.drained[0];
+        self.drained[1] += other.drained[1];
     }
 }
 
@@ -407,7 +848,8 @@ impl EquipStat {
                 diff,
                 ..Default::default()
             }
-        } else if !self.flag.is_empty() {
+        } else if self.is_accessory() {
+            // Accessory replaces the current one
             let flag = (player.flag & PlayerFlag::ACCESSORY_MASK) ^ self.flag;
             let equip = EquipStat {
                 flag,
@@ -497,28 +939,35 @@ impl EquipStat {
     }
 }
 
-// TODO support percent damage
 // Boost health by applying multiplier to existing stats
 #[derive(Clone)]
-struct HpBoostStat {
-    mult: PlayerCombat,
+pub struct HpBoostStat {
+    pub(super) mult: PlayerCombat,
 }
 
 impl HpBoostStat {
-    fn percent_floor<T: Into<i32>>(mult: T, num: T) -> i32 {
-        let prod = mult.into() * num.into();
-        if prod >= 0 {
-            prod / 100
-        } else {
-            (prod - 99) / 100
+    // Multipliers are percentages of the corresponding player stat
+    pub fn new(atk: i16, def: i16, equip_atk: i16, equip_def: i16) -> Self {
+        let equip = EquipStat {
+            atk: equip_atk,
+            def: equip_def,
+            ..Default::default()
+        };
+        Self {
+            mult: PlayerCombat {
+                atk,
+                def,
+                equip,
+                ..Default::default()
+            },
         }
     }
 
     fn probe(&self, player: &PlayerCombat) -> ProbeStat {
-        let hp = Self::percent_floor(self.mult.atk, player.atk)
-            + Self::percent_floor(self.mult.def, player.def)
-            + Self::percent_floor(self.mult.equip.atk, player.equip.atk)
-            + Self::percent_floor(self.mult.equip.def, player.equip.def);
+        let hp = percent_floor(self.mult.atk, player.atk)
+            + percent_floor(self.mult.def, player.def)
+            + percent_floor(self.mult.equip.atk, player.equip.atk)
+            + percent_floor(self.mult.equip.def, player.equip.def);
 
         let diff = PlayerStat {
             hp,
@@ -534,6 +983,7 @@ impl HpBoostStat {
             ProbeStat {
                 diff: diff.clone(),
                 req: -diff,
+                ..Default::default()
             }
         }
     }
@@ -541,7 +991,8 @@ impl HpBoostStat {
 
 // Monster behavior that affect combat
 bitflags! {
-    struct MonsterFlag: u16 {
+    #[derive(Default)]
+    pub struct MonsterFlag: u16 {
         const ONE_HIT               = 0b0000000001;
         const ATTACK_FIRST          = 0b0000000010;
         const SURPRISED_FROM_BEHIND = 0b0000000100;
@@ -555,38 +1006,121 @@ bitflags! {
     }
 }
 
+impl MonsterFlag {
+    // Look up flag by the name used in level files
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ONE_HIT" => Some(Self::ONE_HIT),
+            "ATTACK_FIRST" => Some(Self::ATTACK_FIRST),
+            "SURPRISED_FROM_BEHIND" => Some(Self::SURPRISED_FROM_BEHIND),
+            "ATTACK_LAST" => Some(Self::ATTACK_LAST),
+            "NO_ENEMY_DEFENSE" => Some(Self::NO_ENEMY_DEFENSE),
+            "HAS_WEAPON" => Some(Self::HAS_WEAPON),
+            "GOBLIN_WEAKNESS" => Some(Self::GOBLIN_WEAKNESS),
+            "WYRM_WEAKNESS" => Some(Self::WYRM_WEAKNESS),
+            "BRAINED" => Some(Self::BRAINED),
+            "BRAINED2" => Some(Self::BRAINED2),
+            _ => None,
+        }
+    }
+}
+
 // Stats of a monster
-#[derive(Clone)]
-struct MonsterStat {
-    flag: MonsterFlag,
-    hp: i32,
-    atk: i16,
-    def: i16,
-    gr: i16,
+#[derive(Clone, Default, Eq, Hash, PartialEq)]
+pub struct MonsterStat {
+    pub flag: MonsterFlag,
+    pub hp: i32,
+    pub atk: i16,
+    pub def: i16,
+    pub gr: i16,
+    pub rep: i16,
+    pub drain_atk: i16,      // ATK taken from the player for every hit landed
+    pub drain_def: i16,      // DEF taken from the player for every hit landed
+    pub pierce: i16,         // Percentage of player DEF ignored, rounded down
+    pub regen: i32,          // HP regained after every hit the monster survives
+    pub immune: PlayerFlag,  // Weapons with any of these flags do not hurt the monster
+    pub require: PlayerFlag, // Flags the player needs to fight the monster
 }
 
 impl MonsterStat {
+    // Test results of fighting monster with each way of equipping owned items that is not
+    // dominated by another one, from the best fight with the current equipment kept on a tie.
+    // A way that loses more HP may gain more GR or REP, or drain less, so all are kept.
+    // The current equipment is equipped again after the fight.
+    fn probe_with_items(&self, player: &PlayerCombat, items: &[EquipStat]) -> Vec<ProbeStat> {
+        let mut probes = vec![self.probe(player)];
+        for (equips, combat) in player.loadouts(items) {
+            probes.push(ProbeStat {
+                equips,
+                ..self.probe(&combat)
+            });
+        }
+        probes.sort_by_key(|probe| Reverse(probe.fight_key()));
+        let mut kept: Vec<ProbeStat> = Vec::new();
+        for probe in probes {
+            if !kept.iter().any(|other| other.dominates(&probe)) {
+                kept.push(probe);
+            }
+        }
+        kept
+    }
+
     // Test result of fighting monster
     fn probe(&self, player: &PlayerCombat) -> ProbeStat {
         if player.flag.contains(PlayerFlag::DEAD) {
             return ProbeStat::default();
         }
 
-        let player_atk = if player.flag.contains(PlayerFlag::DOUBLE_ATK_AGAINST_GOBLIN)
-            && self.flag.contains(MonsterFlag::GOBLIN_WEAKNESS)
-            || player.flag.contains(PlayerFlag::DOUBLE_ATK_AGAINST_WYRM)
-                && self.flag.contains(MonsterFlag::WYRM_WEAKNESS)
-        {
-            player.atk as i32 * 2
-        } else {
-            player.atk as i32
+        let fight = match self.fight(player) {
+            Some(fight) => fight,
+            None => return ProbeStat::dead(),
         };
-
-        let player_def = if self.flag.contains(MonsterFlag::NO_ENEMY_DEFENSE) {
-            0
-        } else {
-            player.def as i32
+        let combat = PlayerCombat {
+            atk: -fight.drain_atk,
+            def: -fight.drain_def,
+            ..Default::default()
         };
+        let diff = PlayerStat {
+            hp: -fight.hp_cost,
+            combat,
+            gr: fight.gr,
+            rep: fight.rep,
+            ..Default::default()
+        };
+        let req = PlayerStat {
+            hp: fight.hp_cost,
+            ..Default::default()
+        };
+        ProbeStat {
+            diff,
+            req,
+            drained: [fight.drain_atk, fight.drain_def],
+            ..Default::default()
+        }
+    }
+
+    // Outcome of fighting monster, or None when the player cannot win
+    pub(super) fn fight(&self, player: &PlayerCombat) -> Option<FightStat> {
+        // Cannot fight monster without the required accessory
+        if !player.flag.contains(self.require) {
+            return None;
+        }
+
+        // Player fights as if unarmed when the monster is immune to the weapon
+        let (player_flag, weapon_atk) =
+            if (player.equip.flag & PlayerFlag::WEAPON_MASK).intersects(self.immune) {
+                (
+                    player.flag & !PlayerFlag::WEAPON_MASK,
+                    player.equip.atk as i32,
+                )
+            } else {
+                (player.flag, 0)
+            };
+
+        let double_atk = player_flag.contains(PlayerFlag::DOUBLE_ATK_AGAINST_GOBLIN)
+            && self.flag.contains(MonsterFlag::GOBLIN_WEAKNESS)
+            || player_flag.contains(PlayerFlag::DOUBLE_ATK_AGAINST_WYRM)
+                && self.flag.contains(MonsterFlag::WYRM_WEAKNESS);
 
         let monster_atk = if self.flag.contains(MonsterFlag::BRAINED2) {
             self.atk as i32 * 4
@@ -596,84 +1130,157 @@ impl MonsterStat {
             self.atk as i32
         };
 
-        let monster_def = self.def as i32;
-        let monster_hp = self.hp;
-
-        // Cannot fight monster
-        if player_atk <= monster_def {
-            let combat = PlayerCombat {
-                flag: PlayerFlag::DEAD,
-                ..Default::default()
+        // Damage of a hit of the player and of the monster after the given ATK and DEF
+        // are drained from the player
+        let player_damage = |drained_atk: i16| {
+            let player_atk = (player.atk - drained_atk) as i32 - weapon_atk;
+            let player_atk = if double_atk {
+                player_atk * 2
+            } else {
+                player_atk
             };
-            let dead = PlayerStat {
-                combat,
-                ..Default::default()
-            };
-            return ProbeStat {
-                diff: dead.clone(),
-                req: dead,
+            player_atk - self.def as i32
+        };
+        let monster_damage = |drained_def: i16| {
+            let player_def = if self.flag.contains(MonsterFlag::NO_ENEMY_DEFENSE) {
+                0
+            } else {
+                let def = (player.def - drained_def) as i32;
+                def - def * self.pierce as i32 / 100
             };
+            (monster_atk - player_def).max(0)
+        };
+
+        // Cannot fight monster, or cannot outdamage its regeneration
+        let monster_hp = self.hp;
+        let damage = player_damage(0);
+        if damage <= 0 || damage <= self.regen && damage < monster_hp {
+            return None;
         }
 
-        let hp_cost = if player_def >= monster_atk {
-            // Monster cannot hurt player
-            0
-        } else {
-            // Number of hits when attacking with sword against default monster
-            let mut hits = (monster_hp - 1) / (player_atk - monster_def);
-
-            // Monster attacks first if it has behavior or player attacks without sword
-            if self.flag.contains(MonsterFlag::ATTACK_FIRST)
-                || !player.flag.contains(PlayerFlag::HAS_WEAPON)
-            {
-                hits += 1;
-            }
+        // Monster attacks first if it has behavior or player attacks without sword.
+        // It loses its first hits when it attacks last or is attacked from behind,
+        // but stepping on a sword causes damage before starting combat.
+        let first_hit = (self.flag.contains(MonsterFlag::ATTACK_FIRST)
+            || !player_flag.contains(PlayerFlag::HAS_WEAPON)) as i32;
+        let lost_hits = self.flag.contains(MonsterFlag::ATTACK_LAST) as i32
+            + self.flag.contains(MonsterFlag::SURPRISED_FROM_BEHIND) as i32;
+        let sword_hit = self.flag.contains(MonsterFlag::HAS_WEAPON) as i32;
 
-            if self.flag.contains(MonsterFlag::ATTACK_LAST) && hits > 0 {
-                hits -= 1;
-            }
+        // Draining stops at zero and leaves equipment alone
+        let max_drain_atk = (player.atk - player.equip.atk).max(0);
+        let max_drain_def = (player.def - player.equip.def).max(0);
 
-            // Monster attacked from behind loses its first hit
-            if self.flag.contains(MonsterFlag::SURPRISED_FROM_BEHIND) && hits > 0 {
-                hits -= 1;
-            }
+        let (hits, hp_cost, drain_atk, drain_def) = if self.flag.contains(MonsterFlag::ONE_HIT) {
+            // Take a single hit from automatic attack or sword without fighting
+            (
+                1,
+                monster_damage(0),
+                self.drain_atk.min(max_drain_atk),
+                self.drain_def.min(max_drain_def),
+            )
+        } else if self.drain_atk == 0 && self.drain_def == 0 {
+            // Number of hits when attacking with sword against default monster,
+            // which regenerates after every hit but the last
+            let strikes = if damage >= monster_hp {
+                0
+            } else {
+                let net_damage = damage - self.regen;
+                (monster_hp - damage + net_damage - 1) / net_damage
+            };
+            let hits = (strikes + first_hit - lost_hits).max(0) + sword_hit;
 
-            // Stepping on a sword causes damage before starting combat
-            if self.flag.contains(MonsterFlag::HAS_WEAPON) {
-                hits += 1;
-            }
+            // Monster cannot hurt player when its attack is not above player DEF
+            (hits, hits * monster_damage(0), 0, 0)
+        } else {
+            // Every hit of the monster drains the player, which weakens the following hits
+            // of the player and strengthens those of the monster, so the fight is played out
+            let mut hits = 0;
+            let mut hp_cost = 0;
+            let mut drain_atk = 0;
+            let mut drain_def = 0;
+            let mut monster_hp = monster_hp;
+            let mut landed = sword_hit + (first_hit - lost_hits).max(0);
+            let mut lost_hits = (lost_hits - first_hit).max(0);
+            loop {
+                for _ in 0..landed {
+                    hits += 1;
+                    hp_cost += monster_damage(drain_def);
+                    drain_atk = (drain_atk + self.drain_atk).min(max_drain_atk);
+                    drain_def = (drain_def + self.drain_def).min(max_drain_def);
+                }
+                let damage = player_damage(drain_atk);
+                if damage <= 0 || damage <= self.regen && damage < monster_hp {
+                    return None;
+                }
+                monster_hp -= damage;
+                if monster_hp <= 0 {
+                    break;
+                }
+                monster_hp += self.regen;
 
-            // Take a single hit from automatic attack or sword without fighting
-            if self.flag.contains(MonsterFlag::ONE_HIT) {
-                hits = 1;
+                // Monster hits back after every strike but the last, unless it loses the hit
+                landed = if lost_hits > 0 {
+                    lost_hits -= 1;
+                    0
+                } else {
+                    1
+                };
             }
-            hits * (monster_atk - player_def)
+            (hits, hp_cost, drain_atk, drain_def)
         };
 
+        // Lucky weapon and accessory do not stack
         let monster_gr = if self.flag.contains(MonsterFlag::ONE_HIT) {
             0
-        } else if player.flag.contains(PlayerFlag::DOUBLE_GR_WEAPON) {
+        } else if player_flag.intersects(PlayerFlag::DOUBLE_GR_MASK) {
             self.gr * 2
         } else {
             self.gr
         };
+        let monster_rep = if self.flag.contains(MonsterFlag::ONE_HIT) {
+            0
+        } else if player_flag.contains(PlayerFlag::DOUBLE_REP_ACCESSORY) {
+            self.rep * 2
+        } else {
+            self.rep
+        };
 
-        let diff = PlayerStat {
-            hp: -hp_cost,
+        Some(FightStat {
+            hits,
+            hp_cost,
             gr: monster_gr,
-            ..Default::default()
-        };
-        let req = PlayerStat {
-            hp: hp_cost,
-            ..Default::default()
-        };
-        ProbeStat { diff, req }
+            rep: monster_rep,
+            drain_atk,
+            drain_def,
+        })
+    }
+}
+
+// Outcome of fighting a monster
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FightStat {
+    pub hits: i32, // Hits the monster lands on the player
+    pub hp_cost: i32,
+    pub gr: i16,
+    pub rep: i16,
+    pub drain_atk: i16,
+    pub drain_def: i16,
+}
+
+impl Display for FightStat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hits: {}, hp_cost: {}, gr: {}, rep: {}, drain_atk: {}, drain_def: {}",
+            self.hits, self.hp_cost, self.gr, self.rep, self.drain_atk, self.drain_def
+        )
     }
 }
 
 // Elements in a room that affect player
 #[derive(Clone)]
-enum Element {
+pub enum Element {
     Resource(PlayerStat),    // Give player resources
     Cost(PlayerStat),        // Remove player resources
     Requirement(PlayerStat), // Require certain stats
@@ -687,26 +1294,100 @@ enum Element {
         accessory: bool,
     },
     HpBoost(HpBoostStat),
+    HpPercent(i32),        // Gain or lose a percentage of current HP, rounded down
+    HpSet(i32),            // Set HP to a value
+    SetCounter(usize, i8), // Set counter with the index to a value
+    AddCounter(usize, i8), // Add a value to counter with the index
+    RequireCounter(usize, i8, i8), // Require counter with the index to be in an inclusive range
+    Price(i16),            // Pay GR at a shop, which the equipped accessory may lower
+    Choice(Vec<Branch>),   // Complete exactly one of the branches
+}
+
+// Named sequence of elements picked by a choice
+#[derive(Clone)]
+pub struct Branch {
+    pub name: String,
+    pub content: Vec<Element>,
 }
 
 impl Element {
-    // Test results of passing room element
-    fn probe(&self, player: &PlayerCombat) -> ProbeStat {
+    // Shop selling goods for GR, once for each price in the order given.
+    // Every number of purchases is a branch named after it.
+    pub fn shop(name: &str, goods: PlayerStat, prices: &[i16]) -> Self {
+        let mut branches = Vec::new();
+        let mut content = Vec::new();
+        for count in 0..=prices.len() {
+            let name = if name.is_empty() {
+                format!("bought {}", count)
+            } else {
+                format!("bought {} {}", count, name)
+            };
+            branches.push(Branch {
+                name,
+                content: content.clone(),
+            });
+            if let Some(&price) = prices.get(count) {
+                content.push(Self::Price(price));
+                content.push(Self::Resource(goods.clone()));
+            }
+        }
+        Self::Choice(branches)
+    }
+
+    // Index of the counter used by the element
+    pub(super) fn counter(&self) -> Option<usize> {
+        match self {
+            Self::SetCounter(index, _)
+            | Self::AddCounter(index, _)
+            | Self::RequireCounter(index, _, _) => Some(*index),
+            _ => None,
+        }
+    }
+
+    // Test results of passing room element other than a choice,
+    // given the weapons and accessories of the level
+    fn probe(&self, player: &PlayerCombat, items: &[EquipStat]) -> ProbeStat {
         match self {
             Self::Resource(resource) => ProbeStat {
                 diff: resource.clone(),
-                req: PlayerStat::default(),
+                ..Default::default()
             },
             Self::Cost(cost) => ProbeStat {
                 diff: -cost.clone(),
                 req: cost.clone(),
+                ..Default::default()
             },
             Self::Requirement(req) => ProbeStat {
-                diff: PlayerStat::default(),
                 req: req.clone(),
+                ..Default::default()
             },
-            Self::Monster(monster) => monster.probe(player),
-            Self::Equipment(equip) => equip.probe(&player.equip),
+            Self::Price(price) => {
+                // Half price is rounded up
+                let gr = if player.flag.contains(PlayerFlag::HALF_PRICE_ACCESSORY) {
+                    (price + 1) / 2
+                } else {
+                    *price
+                };
+                let cost = PlayerStat {
+                    gr,
+                    ..Default::default()
+                };
+                ProbeStat {
+                    diff: -cost.clone(),
+                    req: cost,
+                    ..Default::default()
+                }
+            }
+            // Other ways of equipping items are probed along with the sequence
+            Self::Monster(monster) => monster.probe_with_items(player, items).swap_remove(0),
+            Self::Equipment(equip) => {
+                // Weapons and accessories are kept after picking them up
+                let mut probe = equip.probe(&player.equip);
+                if let Some(index) = items.iter().position(|item| item == equip) {
+                    probe.diff.combat.items = 1 << index & !player.items;
+                }
+                probe
+            }
             Self::Inventory {
                 equip,
                 weapon,
@@ -720,13 +1401,22 @@ impl Element {
                 }
             }
             Self::HpBoost(boost) => boost.probe(player),
+            Self::HpPercent(percent) => ProbeStat::hp_op(HpOp::Percent(*percent)),
+            Self::HpSet(hp) => ProbeStat::hp_op(HpOp::Set(*hp)),
+            Self::SetCounter(index, value) => ProbeStat::counter_op(CounterOp::Set(*index, *value)),
+            Self::AddCounter(index, value) => ProbeStat::counter_op(CounterOp::Add(*index, *value)),
+            Self::RequireCounter(index, min, max) => {
+                ProbeStat::counter_op(CounterOp::Require(*index, *min, *max))
+            }
+            Self::Choice(_) => unreachable!("choices are probed along with their sequence"),
         }
     }
 }
 
 // Special ways room should be treated when visiting
 bitflags! {
-    pub(super) struct RoomType: u8 {
+    #[derive(Default)]
+    pub struct RoomType: u8 {
         const INTERMEDIATE      = 0b000001;
         const ONLY_WHEN_FREE    = 0b000010;
         const PRIORITY          = 0b000100;
@@ -739,15 +1429,35 @@ bitflags! {
     }
 }
 
+impl RoomType {
+    // Look up room type by the name used in level files
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "INTERMEDIATE" => Some(Self::INTERMEDIATE),
+            "ONLY_WHEN_FREE" => Some(Self::ONLY_WHEN_FREE),
+            "PRIORITY" => Some(Self::PRIORITY),
+            "DELAYED" => Some(Self::DELAYED),
+
+            #[cfg(feature = "closed-level")]
+            "REPEATED" => Some(Self::REPEATED),
+            #[cfg(feature = "closed-level")]
+            "CLEAR_NEIGHBORS" => Some(Self::CLEAR_NEIGHBORS),
+
+            _ => None,
+        }
+    }
+}
+
 // Sequence of elements that must all be completed
-pub(super) struct Room {
-    pub(super) name: String,
-    content: Vec<Element>,
-    pub(super) room_type: RoomType,
+#[derive(Clone)]
+pub struct Room {
+    pub name: String,
+    pub content: Vec<Element>,
+    pub room_type: RoomType,
 }
 
 impl Room {
-    pub(super) fn new(name: String) -> Self {
+    pub fn new(name: String) -> Self {
         Self {
             name,
             content: Vec::new(),
@@ -755,38 +1465,187 @@ impl Room {
         }
     }
 
-    // Test result of going through each element
-    pub(super) fn probe(&self, player: &PlayerCombat) -> ProbeStat {
-        let mut stat = player.clone();
-        let mut res = ProbeStat::default();
-        for element in &self.content {
-            let probe = element.probe(&stat);
-            res += &probe;
-            stat += &probe.diff.combat;
+    // Test result of going through each element, for every way of making the choices,
+    // given the weapons and accessories of the level. The first choice changes slowest.
+    // Each way is followed by the other ways of equipping owned items for the fights that
+    // are not dominated, as long as the room has fewer than MAX_CHOICE_COUNT results.
+    pub(super) fn probe(&self, player: &PlayerCombat, items: &[EquipStat]) -> Vec<ProbeStat> {
+        let extra = MAX_CHOICE_COUNT.saturating_sub(self.choice_count());
+        keep_undominated(
+            probe_sequence(&self.content, player, items),
+            |probe| probe,
+            extra,
+        )
+    }
+
+    // Picked branch names of every way of making the choices, in the order of probe
+    pub(super) fn choice_names(&self) -> Vec<String> {
+        choice_names(&self.content)
+    }
+
+    // Number of ways of making the choices, saturating at usize::MAX
+    pub(super) fn choice_count(&self) -> usize {
+        choice_count(&self.content)
+    }
+
+    // Every element including those in branches
+    pub(super) fn elements(&self) -> Vec<&Element> {
+        let mut elements = Vec::new();
+        flatten(&self.content, &mut elements);
+        elements
+    }
+}
+
+fn probe_sequence(
+    content: &[Element],
+    player: &PlayerCombat,
+    items: &[EquipStat],
+) -> Vec<ProbeStat> {
+    let mut results = vec![(ProbeStat::default(), player.clone())];
+    for element in content {
+        let count = choice_count(slice::from_ref(element));
+        let mut next = Vec::new();
+        for (res, stat) in &results {
+            let probes = match element {
+                Element::Choice(branches) => {
+                    // Ways of making the choices of each branch follow those of the previous one
+                    let mut probes = Vec::new();
+                    let mut offset = 0;
+                    for branch in branches {
+                        for mut probe in probe_sequence(&branch.content, stat, items) {
+                            probe.choice += offset;
+                            probes.push(probe);
+                        }
+                        offset += choice_count(&branch.content);
+                    }
+                    probes
+                }
+                Element::Monster(monster) => monster.probe_with_items(stat, items),
+                _ => vec![element.probe(stat, items)],
+            };
+            for probe in probes {
+                let mut res = res.clone();
+                res += &probe;
+                res.choice = res.choice * count + probe.choice;
+                let mut stat = stat.clone();
+                stat += &probe.diff.combat;
+                next.push((res, stat));
+            }
+        }
+        results = keep_undominated(next, |(res, _)| res, usize::MAX);
+    }
+    results.into_iter().map(|(res, _)| res).collect()
+}
+
+// Results ordered by the way of making the choices. The first result of each way is kept,
+// followed by at most `extra` others in all that no kept result of the same way dominates.
+fn keep_undominated<T>(
+    mut results: Vec<T>,
+    probe: impl Fn(&T) -> &ProbeStat,
+    mut extra: usize,
+) -> Vec<T> {
+    results.sort_by_key(|res| probe(res).choice);
+    let mut kept: Vec<T> = Vec::with_capacity(results.len());
+    for res in results {
+        let choice = probe(&res).choice;
+        let mut same_choice = kept
+            .iter()
+            .rev()
+            .map(&probe)
+            .take_while(|other| other.choice == choice)
+            .peekable();
+        if same_choice.peek().is_none() {
+            kept.push(res);
+        } else if extra > 0 && !same_choice.any(|other| other.dominates(probe(&res))) {
+            extra -= 1;
+            kept.push(res);
         }
-        res
     }
+    kept
+}
+
+fn choice_names(content: &[Element]) -> Vec<String> {
+    let mut names = vec![String::new()];
+    for element in content {
+        if let Element::Choice(branches) = element {
+            let mut next = Vec::new();
+            for name in &names {
+                for branch in branches {
+                    for branch_name in choice_names(&branch.content) {
+                        let mut name = name.clone();
+                        for part in [&branch.name, &branch_name].iter() {
+                            if !part.is_empty() {
+                                if !name.is_empty() {
+                                    name += ", ";
+                                }
+                                name += part;
+                            }
+                        }
+                        next.push(name);
+                    }
+                }
+            }
+            names = next;
+        }
+    }
+    names
+}
+
+fn choice_count(content: &[Element]) -> usize {
+    content
+        .iter()
+        .fold(1, |count: usize, element| match element {
+            Element::Choice(branches) => {
+                count.saturating_mul(branches.iter().fold(0, |sum: usize, branch| {
+                    sum.saturating_add(choice_count(&branch.content))
+                }))
+            }
+            _ => count,
+        })
+}
+
+fn flatten<'a>(content: &'a [Element], elements: &mut Vec<&'a Element>) {
+    for element in content {
+        elements.push(element);
+        if let Element::Choice(branches) = element {
+            for branch in branches {
+                flatten(&branch.content, elements);
+            }
+        }
+    }
+}
+
+// Problems found while connecting rooms
+#[derive(Debug)]
+pub(super) enum BuildError {
+    DuplicateRoom(String),
+    UnknownRoom(String),
 }
 
 // TODO split into builder
 // Represent level as a graph of rooms
-pub(super) struct Level {
+pub(super) struct Level<const N: usize> {
     pub(super) next_id: VertexIDType,
-    vertices_mask: BitSet,
-    pub(super) neighbors: Vec<BitSet>,
-    pub(super) toggle_neighbors: Vec<BitSet>,
+    vertices_mask: BitSet<N>,
+    pub(super) neighbors: Vec<BitSet<N>>,
+    pub(super) toggle_neighbors: Vec<BitSet<N>>,
     use_edge: bool,
     current_vertex_id: VertexIDType,
     name2id: HashMap<String, VertexIDType>,
     vertices: Vec<Room>,
     pub(super) entrance: VertexIDType,
     pub(super) exit: VertexIDType,
+    pub(super) score: ScoreFunction,
+    pub(super) keys: Vec<String>,     // Names of the key types
+    pub(super) items: Vec<EquipStat>, // Weapons and accessories players keep once picked up
+    pub(super) item_names: Vec<String>,
+    pub(super) drains: bool, // Whether some monster drains ATK or DEF
 
     #[cfg(feature = "closed-level")]
-    pub(super) boundary_mask: BitSet,
+    pub(super) boundary_mask: BitSet<N>,
 }
 
-impl Level {
+impl<const N: usize> Level<N> {
     pub fn new() -> Self {
         Self {
             next_id: 0,
@@ -794,11 +1653,16 @@ impl Level {
             neighbors: Vec::new(),
             toggle_neighbors: Vec::new(),
             use_edge: false,
-            entrance: u8::MAX,
-            exit: u8::MAX,
-            current_vertex_id: u8::MAX,
+            entrance: VertexIDType::MAX,
+            exit: VertexIDType::MAX,
+            current_vertex_id: VertexIDType::MAX,
             name2id: HashMap::new(),
             vertices: Vec::new(),
+            score: ScoreFunction::drod(),
+            keys: default_keys(),
+            items: Vec::new(),
+            item_names: Vec::new(),
+            drains: false,
 
             #[cfg(feature = "closed-level")]
             boundary_mask: BitSet::new(),
@@ -810,14 +1674,13 @@ impl Level {
         self
     }
 
-    //     fn select_name(&mut self, name: &str) -> &mut Self {
-    //         self.select_id(self.id(name))
-    //     }
+    pub(super) fn select_name(&mut self, name: &str) -> Result<&mut Self, BuildError> {
+        Ok(self.select_id(self.id(name)?))
+    }
 
-    fn select_room(&mut self, room: Room) -> &mut Self {
+    pub(super) fn select_room(&mut self, room: Room) -> Result<&mut Self, BuildError> {
         if self.name2id.contains_key(&room.name) {
-            // TODO improve error reporting
-            panic!(String::from("the room has aleady been added: ") + &room.name);
+            return Err(BuildError::DuplicateRoom(room.name));
         }
         self.vertices_mask.set_bit(self.next_id as usize, true);
 
@@ -835,7 +1698,7 @@ impl Level {
         self.vertices.push(room);
         self.neighbors.push(BitSet::new());
         self.toggle_neighbors.push(BitSet::new());
-        self
+        Ok(self)
     }
 
     fn add_arc(&mut self, id0: VertexIDType, id1: VertexIDType) -> &mut Self {
@@ -854,37 +1717,37 @@ impl Level {
         self.add_arc(id0, id1)
     }
 
-    pub(super) fn add_name(&mut self, name: &str) -> &mut Self {
-        self.add_id(self.id(name))
+    pub(super) fn add_name(&mut self, name: &str) -> Result<&mut Self, BuildError> {
+        Ok(self.add_id(self.id(name)?))
     }
 
-    pub(super) fn add_room(&mut self, room: Room) -> &mut Self {
-        let id0 = self.current_vertex_id;
-        let id1 = self.select_room(room).current_vertex_id;
-        if self.use_edge {
-            self.add_arc(id1, id0);
+    fn toggle(&mut self, id0: VertexIDType, id1: VertexIDType) -> &mut Self {
+        if id0 < self.next_id && id1 < self.next_id {
+            self.toggle_neighbors[id0 as usize].set_bit(id1 as usize, true)
         }
-        self.add_arc(id0, id1)
+        self
     }
 
-    //     fn toggle(&mut self, id0: VertexIDType, id1: VertexIDType) -> &mut Self {
-    //         if id0 < self.next_id && id1 < self.next_id {
-    //             self.toggle_neighbors[id0 as usize].set_bit(id1 as usize, true)
-    //         }
-    //         self
-    //     }
+    pub(super) fn toggle_name(
+        &mut self,
+        name0: &str,
+        name1: &str,
+    ) -> Result<&mut Self, BuildError> {
+        Ok(self.toggle(self.id(name0)?, self.id(name1)?))
+    }
 
-    //     fn toggle_name(&mut self, name0: &str, name1: &str) -> &mut Self {
-    //         self.toggle(self.id(name0), self.id(name1))
-    //     }
+    pub(super) fn find_name(&self, name: &str) -> Option<VertexIDType> {
+        self.name2id.get(name).copied()
+    }
 
-    fn id(&self, name: &str) -> VertexIDType {
+    fn id(&self, name: &str) -> Result<VertexIDType, BuildError> {
         let id = self.name2id.get(name);
-        *id.expect(&(String::from("cannot find vertex with given name: ") + name))
+        id.copied()
+            .ok_or_else(|| BuildError::UnknownRoom(name.to_owned()))
     }
 
     //     fn reset(&mut self) -> &mut Self {
-    //         self.current_vertex_id = u8::MAX;
+    //         self.current_vertex_id = VertexIDType::MAX;
     //         self
     //     }
 
@@ -896,6 +1759,23 @@ impl Level {
         &self.vertices[id as usize]
     }
 
+    // Test result of going through the room for every way of making its choices
+    pub(super) fn probe(&self, id: VertexIDType, player: &PlayerCombat) -> Vec<ProbeStat> {
+        self.vertex_of_id(id).probe(player, &self.items)
+    }
+
+    // Names of the items equipped for fights, each in the order it is first equipped
+    pub(super) fn equip_names(&self, equips: &[u8]) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for &index in equips {
+            let name = &self.item_names[index as usize];
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+
     //     fn vertex_of_name(&self, name: &str) -> &Room {
     //         self.vertex_of_id(self.id(name))
     //     }
@@ -910,8 +1790,8 @@ impl Level {
         self
     }
 
-    pub(super) fn set_entrance_name(&mut self, name: &str) -> &mut Self {
-        self.set_entrance_id(self.id(name))
+    pub(super) fn set_entrance_name(&mut self, name: &str) -> Result<&mut Self, BuildError> {
+        Ok(self.set_entrance_id(self.id(name)?))
     }
 
     //     fn set_exit(&mut self) -> &mut Self {
@@ -924,8 +1804,8 @@ impl Level {
         self
     }
 
-    pub(super) fn set_exit_name(&mut self, name: &str) -> &mut Self {
-        self.set_exit_id(self.id(name))
+    pub(super) fn set_exit_name(&mut self, name: &str) -> Result<&mut Self, BuildError> {
+        Ok(self.set_exit_id(self.id(name)?))
     }
 }
 
@@ -939,6 +1819,10 @@ impl PlayerScore {
     pub(super) fn new() -> Self {
         Self { score: 0 }
     }
+
+    pub(super) fn points(&self) -> f64 {
+        self.score as f64 / 1000.0
+    }
 }
 
 impl Ge for PlayerScore {
@@ -963,48 +1847,524 @@ impl Display for PlayerScore {
     }
 }
 
-// TODO support multiple configs
-pub struct LevelInfo {
-    pub(super) max_config_number: i32,
-    data: Value,
+// Linear combination of player stats divided by divisor, with weights scaled by 1000
+#[derive(Clone)]
+pub struct ScoreFunction {
+    pub hp: i32,
+    pub atk: i32,
+    pub def: i32,
+    pub equip_atk: i32,
+    pub equip_def: i32,
+    pub gr: i32,
+    pub rep: i32,
+    pub keys: [i32; MAX_KEY_COUNT], // Weights of the key types in the order the level defines them
+    pub divisor: i32,
 }
 
-impl LevelInfo {
-    pub fn new(data: Value) -> io::Result<Self> {
-        Ok(Self {
-            max_config_number: 1,
-            data,
-        })
+impl ScoreFunction {
+    // Score of DROD RPG
+    pub fn drod() -> Self {
+        Self {
+            hp: 25,
+            atk: 5000,
+            def: 3000,
+            keys: key_weights(&[10000, 20000, 30000]),
+            ..Default::default()
+        }
     }
 
-    pub(super) fn init_player(&self) -> PlayerStat {
-        PlayerStat::with_stat(500, 10, 10)
+    // Score of Tendry's Tall Tales, where DEF is worth 10/3 points
+    pub fn tots() -> Self {
+        Self {
+            hp: 75,
+            atk: 15000,
+            def: 10000,
+            keys: key_weights(&[30000, 60000, 90000]),
+            divisor: 3,
+            ..Default::default()
+        }
     }
 
-    pub(super) fn build(&self, config: i32) -> Level {
-        let mut level = Level::new();
-        level.add_room(Room::new("O".to_owned()));
-        level.set_entrance_name("O");
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "drod" => Some(Self::drod()),
+            "tots" => Some(Self::tots()),
+            _ => None,
+        }
+    }
 
-        level.add_room(Room::new("exit".to_owned()));
-        level.set_exit_name("exit");
+    // Scores past the range of i32 saturate, which keeps them in order
+    pub(super) fn score(&self, stat: &PlayerStat) -> PlayerScore {
+        let combat = stat.as_ref();
+        let score = (stat.hp as i128 + 1) * self.hp as i128
+            + combat.atk as i128 * self.atk as i128
+            + combat.def as i128 * self.def as i128
+            + combat.equip.atk as i128 * self.equip_atk as i128
+            + combat.equip.def as i128 * self.equip_def as i128
+            + stat.gr as i128 * self.gr as i128
+            + stat.rep as i128 * self.rep as i128
+            + stat
+                .keys
+                .iter()
+                .zip(&self.keys)
+                .map(|(&key, &weight)| key as i128 * weight as i128)
+                .sum::<i128>();
+        let score = score / self.divisor as i128;
+        PlayerScore {
+            score: score.clamp(i32::MIN as i128, i32::MAX as i128) as i32,
+        }
+    }
+}
+
+// Weights of the first key types, which are yellow, green and blue in the official game
+fn key_weights(weights: &[i32]) -> [i32; MAX_KEY_COUNT] {
+    let mut keys = [0; MAX_KEY_COUNT];
+    keys[..weights.len()].copy_from_slice(weights);
+    keys
+}
+
+impl Default for ScoreFunction {
+    fn default() -> Self {
+        Self {
+            hp: 0,
+            atk: 0,
+            def: 0,
+            equip_atk: 0,
+            equip_def: 0,
+            gr: 0,
+            rep: 0,
+            keys: [0; MAX_KEY_COUNT],
+            divisor: 1,
+        }
+    }
+}
+
+impl FromStr for ScoreFunction {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::from_name(name).ok_or_else(|| format!("unknown score function \"{}\"", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Player with 20 ATK and 10 DEF of its own, holding a weapon with 10 ATK
+    fn armed(weapon_flag: PlayerFlag) -> PlayerCombat {
+        let flag = PlayerFlag::HAS_WEAPON | weapon_flag;
+        PlayerCombat {
+            flag,
+            atk: 30,
+            def: 10,
+            equip: EquipStat {
+                flag,
+                atk: 10,
+                def: 0,
+            },
+            ..Default::default()
+        }
+    }
+
+    // Monster that takes 4 hits from an armed player and lands 3 of them
+    fn monster() -> MonsterStat {
+        MonsterStat {
+            hp: 100,
+            atk: 20,
+            def: 5,
+            gr: 4,
+            ..Default::default()
+        }
+    }
+
+    fn is_dead(probe: &ProbeStat) -> bool {
+        probe.req.combat.flag.contains(PlayerFlag::DEAD)
+    }
+
+    #[test]
+    fn plain_monster() {
+        let probe = monster().probe(&armed(PlayerFlag::empty()));
+        assert_eq!(probe.diff.hp, -30);
+        assert_eq!(probe.req.hp, 30);
+        assert_eq!(probe.diff.gr, 4);
+        assert_eq!(probe.diff.combat.atk, 0);
+        assert_eq!(probe.diff.combat.def, 0);
+    }
+
+    #[test]
+    fn fight_outcome() {
+        let fight = monster().fight(&armed(PlayerFlag::empty())).unwrap();
+        assert_eq!(fight.hits, 3);
+        assert_eq!(fight.hp_cost, 30);
+        assert_eq!(fight.gr, 4);
+
+        // A monster that cannot be hurt cannot be beaten
+        let mut player = armed(PlayerFlag::empty());
+        player.atk = 5;
+        assert!(monster().fight(&player).is_none());
+    }
+
+    #[test]
+    fn drain_atk_and_def() {
+        let monster = MonsterStat {
+            drain_atk: 2,
+            drain_def: 1,
+            ..monster()
+        };
+        // Every hit weakens the player, so it takes 5 strikes of 25, 23, 21, 19 and 17
+        // and 4 hits of 10, 11, 12 and 13 instead of 4 strikes and 3 hits
+        let probe = monster.probe(&armed(PlayerFlag::empty()));
+        assert_eq!(probe.diff.hp, -46);
+        assert_eq!(probe.diff.combat.atk, -8);
+        assert_eq!(probe.diff.combat.def, -4);
+        assert!(!probe.diff.nonnegative());
+    }
+
+    #[test]
+    fn drain_can_prevent_winning() {
+        // Player deals 5 damage until the first hit and nothing after it
+        let monster = MonsterStat {
+            drain_atk: 5,
+            def: 25,
+            ..monster()
+        };
+        assert!(monster.fight(&armed(PlayerFlag::empty())).is_none());
+
+        // Without the drain it takes 20 strikes
+        let monster = MonsterStat {
+            drain_atk: 0,
+            ..monster
+        };
+        assert_eq!(monster.fight(&armed(PlayerFlag::empty())).unwrap().hits, 19);
+    }
+
+    #[test]
+    fn drain_stops_at_equipment() {
+        // 3 hits would drain 30 ATK but only 20 is not from the weapon
+        let monster = MonsterStat {
+            drain_atk: 10,
+            ..monster()
+        };
+        let probe = monster.probe(&armed(PlayerFlag::empty()));
+        assert_eq!(probe.diff.combat.atk, -20);
+    }
+
+    #[test]
+    fn drain_without_damage() {
+        // Hits drain DEF even when they cannot hurt
+        let monster = MonsterStat {
+            drain_def: 1,
+            ..monster()
+        };
+        let player = PlayerCombat {
+            def: 30,
+            ..armed(PlayerFlag::empty())
+        };
+        let probe = monster.probe(&player);
+        assert_eq!(probe.diff.hp, 0);
+        assert_eq!(probe.diff.combat.def, -3);
+    }
+
+    #[test]
+    fn immune_to_weapon() {
+        // Unarmed player deals 15 damage, so it takes 7 hits including the first one
+        let monster = MonsterStat {
+            immune: PlayerFlag::HAS_WEAPON,
+            ..monster()
+        };
+        let probe = monster.probe(&armed(PlayerFlag::empty()));
+        assert_eq!(probe.diff.hp, -70);
+    }
+
+    #[test]
+    fn immune_to_other_weapon() {
+        let monster = MonsterStat {
+            immune: PlayerFlag::DOUBLE_ATK_AGAINST_WYRM,
+            ..monster()
+        };
+        let probe = monster.probe(&armed(PlayerFlag::empty()));
+        assert_eq!(probe.diff.hp, -30);
+    }
+
+    #[test]
+    fn immune_to_weapon_bonuses() {
+        // Neither double ATK nor double GR of the weapon apply
+        let monster = MonsterStat {
+            flag: MonsterFlag::GOBLIN_WEAKNESS,
+            immune: PlayerFlag::DOUBLE_ATK_AGAINST_GOBLIN,
+            ..monster()
+        };
+        let weapon = PlayerFlag::DOUBLE_ATK_AGAINST_GOBLIN | PlayerFlag::DOUBLE_GR_WEAPON;
+        let probe = monster.probe(&armed(weapon));
+        assert_eq!(probe.diff.hp, -70);
+        assert_eq!(probe.diff.gr, 4);
+
+        let armed_probe = MonsterStat {
+            immune: PlayerFlag::empty(),
+            ..monster
+        }
+        .probe(&armed(weapon));
+        assert_eq!(armed_probe.diff.hp, -10);
+        assert_eq!(armed_probe.diff.gr, 8);
+    }
+
+    #[test]
+    fn immune_to_only_weapon_that_hurts() {
+        let monster = MonsterStat {
+            def: 20,
+            immune: PlayerFlag::HAS_WEAPON,
+            ..monster()
+        };
+        assert!(is_dead(&monster.probe(&armed(PlayerFlag::empty()))));
+    }
+
+    #[test]
+    fn pierce_def() {
+        // Half of 10 DEF is ignored
+        let piercing = MonsterStat {
+            pierce: 50,
+            ..monster()
+        };
+        assert_eq!(piercing.probe(&armed(PlayerFlag::empty())).diff.hp, -45);
 
-        level.add_name("O").add_room(Room::new("U1".to_owned()));
-        level.add_name("O").add_room(Room::new("U2".to_owned()));
-        level.add_name("O").add_room(Room::new("U3".to_owned()));
-        level.add_name("O").add_room(Room::new("U4".to_owned()));
-        level.add_name("O").add_room(Room::new("U5".to_owned()));
-        level.add_name("O").add_room(Room::new("U6".to_owned()));
+        // A quarter of 10 DEF is 2.5, of which 2 is ignored
+        let piercing = MonsterStat {
+            pierce: 25,
+            ..piercing
+        };
+        assert_eq!(piercing.probe(&armed(PlayerFlag::empty())).diff.hp, -36);
 
-        level.add_name("O").add_room(Room::new("L1".to_owned()));
-        level.add_name("O").add_room(Room::new("L2".to_owned()));
-        level.add_name("O").add_room(Room::new("L3".to_owned()));
-        level.add_name("O").add_room(Room::new("L4".to_owned()));
-        level.add_name("O").add_room(Room::new("L5".to_owned()));
+        // Ignoring all DEF is the same as having no enemy defense
+        let piercing = MonsterStat {
+            pierce: 100,
+            ..piercing
+        };
+        let no_defense = MonsterStat {
+            flag: MonsterFlag::NO_ENEMY_DEFENSE,
+            ..monster()
+        };
+        assert_eq!(piercing.probe(&armed(PlayerFlag::empty())).diff.hp, -60);
+        assert_eq!(no_defense.probe(&armed(PlayerFlag::empty())).diff.hp, -60);
+    }
 
-        level.add_name("O").add_room(Room::new("Boss".to_owned()));
-        level
+    #[test]
+    fn regenerate() {
+        // Monster HP goes 100, 80, 60, 40, 20 before each of the 5 hits of the player
+        let monster = MonsterStat {
+            regen: 5,
+            ..monster()
+        };
+        assert_eq!(monster.probe(&armed(PlayerFlag::empty())).diff.hp, -40);
+    }
+
+    #[test]
+    fn regenerate_as_fast_as_damage() {
+        let monster = MonsterStat {
+            regen: 25,
+            ..monster()
+        };
+        assert!(is_dead(&monster.probe(&armed(PlayerFlag::empty()))));
+    }
+
+    #[test]
+    fn regenerate_after_single_hit() {
+        // Regeneration does not matter when the first hit kills
+        let monster = MonsterStat {
+            hp: 25,
+            regen: 100,
+            ..monster()
+        };
+        assert_eq!(monster.probe(&armed(PlayerFlag::empty())).diff.hp, 0);
+    }
+
+    #[test]
+    fn require_accessory() {
+        let monster = MonsterStat {
+            require: PlayerFlag::DOUBLE_REP_ACCESSORY,
+            ..monster()
+        };
+        assert!(is_dead(&monster.probe(&armed(PlayerFlag::empty()))));
+        let probe = monster.probe(&armed(PlayerFlag::DOUBLE_REP_ACCESSORY));
+        assert!(!is_dead(&probe));
+        assert_eq!(probe.diff.hp, -30);
+    }
+
+    #[test]
+    fn double_gr() {
+        let weapon = PlayerFlag::DOUBLE_GR_WEAPON;
+        let accessory = PlayerFlag::DOUBLE_GR_ACCESSORY;
+        assert_eq!(monster().probe(&armed(weapon)).diff.gr, 8);
+        assert_eq!(monster().probe(&armed(accessory)).diff.gr, 8);
+        assert_eq!(monster().probe(&armed(weapon | accessory)).diff.gr, 8);
+    }
+
+    #[test]
+    fn double_rep() {
+        let monster = MonsterStat {
+            rep: 3,
+            ..monster()
+        };
+        let accessory = PlayerFlag::DOUBLE_REP_ACCESSORY;
+        assert_eq!(monster.probe(&armed(PlayerFlag::empty())).diff.rep, 3);
+        assert_eq!(monster.probe(&armed(accessory)).diff.rep, 6);
+        assert_eq!(monster.probe(&armed(accessory)).diff.gr, 4);
+    }
+
+    #[test]
+    fn hp_percent_of_large_hp() {
+        // The product of the percentage and HP does not fit in i32
+        let mut stat = PlayerStat::with_stat(5000, 10, 10);
+        Element::HpPercent(10000)
+            .probe(&stat.combat, &[])
+            .apply(&mut stat);
+        assert_eq!(stat.hp + 1, 505000);
+
+        // HP stops at the largest value instead of wrapping around
+        let mut stat = PlayerStat::with_stat(i32::MAX / 2, 10, 10);
+        Element::HpPercent(200)
+            .probe(&stat.combat, &[])
+            .apply(&mut stat);
+        assert_eq!(stat.hp + 1, i32::MAX);
+        assert_eq!(HpOp::Percent(1000000).apply(5000), 50005000);
+        assert_eq!(HpOp::Percent(10000).apply(i32::MAX), i32::MAX);
+        assert_eq!(HpOp::Add(1).apply(i32::MAX), i32::MAX);
+        assert_eq!(percent_floor(-1, 1), -1);
+    }
+
+    #[test]
+    fn score_saturates() {
+        let stat = PlayerStat::with_stat(1000, 10, 10);
+        assert_eq!(
+            ScoreFunction::drod().score(&stat).score,
+            25000 + 50000 + 30000
+        );
+
+        // 2147483647 HP at 25 points each is past the range of the score
+        let stat = PlayerStat::with_stat(i32::MAX, 10, 10);
+        assert_eq!(ScoreFunction::drod().score(&stat).score, i32::MAX);
+        let score = ScoreFunction {
+            hp: -i32::MAX,
+            atk: i32::MAX,
+            def: i32::MAX,
+            ..Default::default()
+        };
+        assert_eq!(score.score(&stat).score, i32::MIN);
     }
 
-    pub(super) fn print_config(&self, writer: &mut dyn Write, config: i32) {}
+    #[test]
+    fn shop() {
+        let mut room = Room::new("Shop".to_owned());
+        let goods = PlayerStat {
+            hp: 50,
+            ..Default::default()
+        };
+        room.content.push(Element::shop("HP", goods, &[10, 20]));
+        assert_eq!(
+            room.choice_names(),
+            vec!["bought 0 HP", "bought 1 HP", "bought 2 HP"]
+        );
+        let probes = room.probe(&armed(PlayerFlag::empty()), &[]);
+        let bought: Vec<_> = probes
+            .iter()
+            .map(|probe| (probe.diff.hp, probe.diff.gr))
+            .collect();
+        assert_eq!(bought, vec![(0, 0), (50, -10), (100, -30)]);
+        assert_eq!(probes[2].req.gr, 30);
+    }
+
+    #[test]
+    fn shop_with_half_price_accessory() {
+        let mut room = Room::new("Shop".to_owned());
+        room.content
+            .push(Element::shop("ATK", PlayerStat::new(0, 2, 0), &[10, 15]));
+        let prices = |player: &PlayerCombat| -> Vec<i16> {
+            let probes = room.probe(player, &[]);
+            probes.iter().map(|probe| probe.req.gr).collect()
+        };
+        assert_eq!(prices(&armed(PlayerFlag::empty())), [0, 10, 25]);
+
+        // Prices are halved and rounded up while the accessory is equipped
+        let player = armed(PlayerFlag::HALF_PRICE_ACCESSORY);
+        assert_eq!(prices(&player), [0, 5, 13]);
+
+        // An owned accessory that is not equipped does not help
+        let card = EquipStat {
+            flag: PlayerFlag::HALF_PRICE_ACCESSORY,
+            ..Default::default()
+        };
+        let player = PlayerCombat {
+            items: 1,
+            ..armed(PlayerFlag::empty())
+        };
+        assert_eq!(room.probe(&player, &[card])[2].req.gr, 25);
+    }
+
+    #[test]
+    fn swap_accessory() {
+        let greckle = EquipStat {
+            flag: PlayerFlag::DOUBLE_GR_ACCESSORY,
+            ..Default::default()
+        };
+        let vip = EquipStat {
+            flag: PlayerFlag::DOUBLE_REP_ACCESSORY,
+            ..Default::default()
+        };
+        let mut player = armed(PlayerFlag::empty()).equip;
+        player += &greckle.probe(&player).diff.combat.equip;
+        assert_eq!(player.flag, PlayerFlag::HAS_WEAPON | greckle.flag);
+        player += &vip.probe(&player).diff.combat.equip;
+        assert_eq!(player.flag, PlayerFlag::HAS_WEAPON | vip.flag);
+        assert!(vip.probe(&player).diff.combat.equip.flag.is_empty());
+    }
+
+    // Goblin weapon with 15 ATK and plain weapon with 25 ATK
+    fn weapons() -> Vec<EquipStat> {
+        let flag = PlayerFlag::HAS_WEAPON;
+        vec![
+            EquipStat {
+                flag: flag | PlayerFlag::DOUBLE_ATK_AGAINST_GOBLIN,
+                atk: 15,
+                def: 0,
+            },
+            EquipStat {
+                flag,
+                atk: 25,
+                def: 0,
+            },
+        ]
+    }
+
+    #[test]
+    fn fight_with_owned_weapon() {
+        let items = weapons();
+        let goblin = MonsterStat {
+            flag: MonsterFlag::GOBLIN_WEAKNESS,
+            ..monster()
+        };
+        let mut room = Room::new("Armory".to_owned());
+        room.content.push(Element::Equipment(items[0].clone()));
+        room.content.push(Element::Equipment(items[1].clone()));
+        room.content.push(Element::Monster(goblin));
+        let probe = &room.probe(&armed(PlayerFlag::empty()), &items)[0];
+        assert_eq!(probe.equips, vec![0]);
+        assert_eq!(probe.diff.hp, -10);
+        assert_eq!(probe.diff.combat.items, 0b11);
+        assert_eq!(probe.diff.combat.equip.atk, 15);
+    }
+
+    #[test]
+    fn keep_weaker_weapon() {
+        let items = weapons();
+        let mut player = armed(PlayerFlag::empty());
+        for item in items.iter().rev() {
+            let probe = Element::Equipment(item.clone()).probe(&player, &items);
+            player += &probe.diff.combat;
+        }
+        assert_eq!(player.flag, PlayerFlag::HAS_WEAPON);
+        assert_eq!(player.equip.atk, 25);
+        assert_eq!(player.items, 0b11);
+        let probe = Element::Monster(monster()).probe(&player, &items);
+        assert!(probe.equips.is_empty());
+    }
 }
@@ -1,57 +1,65 @@
-use super::model::{Level, LevelInfo, PlayerCombat, PlayerScore, PlayerStat, ProbeStat, RoomType};
-use super::{Ge, VertexIDType};
+mod checkpoint;
+mod verify;
+
+pub use checkpoint::Checkpoint;
+pub use verify::{verify, RouteStep};
+
+use super::bitset::{word_count, BitSet, BitSetIter};
+use super::level_info::LevelInfo;
+use super::model::{
+    Level, PlayerCombat, PlayerScore, PlayerStat, ProbeStat, RoomType, ScoreFunction,
+};
+use super::sharded_map::ShardedMap;
+use super::solution::{ConfigResult, PlayerTrace, SearchCallback, SearchResult, TraceStep};
+use super::{ChoiceIDType, Counters, Ge, VertexIDType};
 
-use rust_dense_bitset::BitSet as _;
-use rust_dense_bitset::DenseBitSet as BitSet;
 use structopt::StructOpt;
 
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use std::i16;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::Write;
+use std::mem;
 use std::ops::{AddAssign, SubAssign};
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
 use std::time::Instant;
-use std::u8;
 
-// An iterator for DenseBitSet that returns the position of each enabled bit in the set
-struct BitSetIter(BitSet);
+#[derive(Clone, Default, Eq, PartialEq)]
+struct PlayerProgress<const N: usize> {
+    visited: BitSet<N>,
+    counters: Counters,
 
-impl From<BitSet> for BitSetIter {
-    fn from(bitset: BitSet) -> Self {
-        Self(bitset)
-    }
-}
-
-impl Iterator for BitSetIter {
-    type Item = u8;
-
-    // Return position of next enabled bit in set
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.0.none() {
-            None
-        } else {
-            let first_set = self.0.first_set();
-            assert!(first_set < 64);
-            self.0.set_bit(first_set as usize, false);
-            Some(first_set as u8)
-        }
-    }
-}
+    // Results picked in visited rooms with more than one, sorted by room. A result is a way
+    // of making the choices of the room and of equipping owned items for its fights.
+    // Rooms visited more than once keep every pick in the order they were made.
+    // Shared between clones and missing when empty to keep situations small.
+    choices: Option<Arc<Vec<(VertexIDType, ChoiceIDType)>>>,
 
-#[derive(Clone, Default, Eq, Hash, PartialEq)]
-struct PlayerProgress {
-    visited: BitSet,
+    // ATK and DEF drained by monsters, which depend on the order of the fights
+    // and so are not given by the rooms visited like the other stats besides HP
+    drained: [i16; 2],
 
     #[cfg(feature = "closed-level")]
-    memory: BitSet,
+    memory: BitSet<N>,
 }
 
-impl SubAssign<&Self> for PlayerProgress {
+// Progress diffs are stored as symmetric differences, except for choices
+#[allow(clippy::suspicious_op_assign_impl)]
+impl<const N: usize> SubAssign<&Self> for PlayerProgress<N> {
     fn sub_assign(&mut self, other: &Self) {
         self.visited ^= other.visited;
+        for (counter, other) in self.counters.iter_mut().zip(&other.counters) {
+            *counter ^= other;
+        }
+        self.drained[0] ^= other.drained[0];
+        self.drained[1] ^= other.drained[1];
 
         #[cfg(feature = "closed-level")]
         {
@@ -60,68 +68,112 @@ impl SubAssign<&Self> for PlayerProgress {
     }
 }
 
-impl SubAssign<&PlayerProgressDiff> for PlayerProgress {
-    fn sub_assign(&mut self, diff: &PlayerProgressDiff) {
+impl<const N: usize> SubAssign<&PlayerProgressDiff<N>> for PlayerProgress<N> {
+    fn sub_assign(&mut self, diff: &PlayerProgressDiff<N>) {
         #[cfg(not(feature = "closed-level"))]
         self.visited.set_bit(diff.location as usize, false);
 
+        if diff.choice.is_some() {
+            let end = self.choices_end(diff.location);
+            if let Some(choices) = &mut self.choices {
+                Arc::make_mut(choices).remove(end - 1);
+                if choices.is_empty() {
+                    self.choices = None;
+                }
+            }
+        }
         *self -= &diff.progress;
     }
 }
 
-impl Display for PlayerProgress {
+// Progress is hashed for every situation found, so counters are hashed as a single word
+// and choices and drained stats only when there are any
+impl<const N: usize> Hash for PlayerProgress<N> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.visited.hash(state);
+        state.write_u64(u64::from_ne_bytes(
+            self.counters.map(|counter| counter as u8),
+        ));
+        if let Some(choices) = &self.choices {
+            choices.hash(state);
+        }
+        if self.drained != [0; 2] {
+            self.drained.hash(state);
+        }
+
+        #[cfg(feature = "closed-level")]
+        self.memory.hash(state);
+    }
+}
+
+impl<const N: usize> PlayerProgress<N> {
+    fn choices(&self) -> &[(VertexIDType, ChoiceIDType)] {
+        self.choices.as_deref().map_or(&[], Vec::as_slice)
+    }
+
+    // Index after the picks made in the room
+    fn choices_end(&self, location: VertexIDType) -> usize {
+        self.choices().partition_point(|&(id, _)| id <= location)
+    }
+
+    fn add_choice(&mut self, location: VertexIDType, choice: ChoiceIDType) {
+        let end = self.choices_end(location);
+        let choices = self.choices.get_or_insert_with(Default::default);
+        Arc::make_mut(choices).insert(end, (location, choice));
+    }
+}
+
+impl<const N: usize> Display for PlayerProgress<N> {
     #[cfg(feature = "closed-level")]
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "visited: {}, memory: {}",
-            self.visited.to_integer(),
-            self.memory.to_integer()
-        )
+        write!(f, "visited: {}, memory: {}", self.visited, self.memory)
     }
 
     #[cfg(not(feature = "closed-level"))]
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "visited: {}", self.visited.to_integer())
+        write!(f, "visited: {}", self.visited)
     }
 }
 
 #[derive(Clone)]
-struct PlayerProgressDiff {
-    progress: PlayerProgress,
+struct PlayerProgressDiff<const N: usize> {
+    progress: PlayerProgress<N>,
     location: VertexIDType,
+    choice: Option<ChoiceIDType>, // Missing when the room has no choices
 }
 
-impl PlayerProgressDiff {
+impl<const N: usize> PlayerProgressDiff<N> {
     fn new() -> Self {
         Self {
             progress: PlayerProgress::default(),
-            location: u8::MAX,
+            location: VertexIDType::MAX,
+            choice: None,
         }
     }
 }
 
-impl Default for PlayerProgressDiff {
+impl<const N: usize> Default for PlayerProgressDiff<N> {
     fn default() -> Self {
         Self {
             progress: PlayerProgress::default(),
-            location: u8::MAX,
+            location: VertexIDType::MAX,
+            choice: None,
         }
     }
 }
 
 #[derive(Clone, Default)]
-pub struct Player {
+pub struct Player<const N: usize> {
     stat: PlayerStat,
-    progress: PlayerProgress,
-    diff: PlayerProgressDiff,
-    neighbors: BitSet,
+    progress: PlayerProgress<N>,
+    diff: PlayerProgressDiff<N>,
+    neighbors: BitSet<N>,
 
     #[cfg(feature = "closed-level")]
-    disabled: BitSet,
+    disabled: BitSet<N>,
 }
 
-impl Player {
+impl<const N: usize> Player<N> {
     pub fn new(hp: i32, atk: i16, def: i16) -> Self {
         Self {
             stat: PlayerStat::with_stat(hp, atk, def),
@@ -134,20 +186,50 @@ impl Player {
         }
     }
 
-    fn reverted_progress(&self) -> PlayerProgress {
+    fn reverted_progress(&self) -> PlayerProgress<N> {
         let mut progress = self.progress.clone();
         progress -= &self.diff;
         progress
     }
 
-    fn enter(&mut self, level: &Level) {
+    // Counter and drain diffs are stored even without the closed-level feature
+    fn apply_progress(&mut self, probe: &ProbeStat) {
+        let old_counters = self.progress.counters;
+        self.progress.counters = probe
+            .counters(&old_counters)
+            .expect("counter requirement not met");
+        for (diff, (new, old)) in self
+            .diff
+            .progress
+            .counters
+            .iter_mut()
+            .zip(self.progress.counters.iter().zip(&old_counters))
+        {
+            *diff = new ^ old;
+        }
+
+        let old_drained = self.progress.drained;
+        for (i, drained) in self.progress.drained.iter_mut().enumerate() {
+            *drained += probe.drained[i];
+            self.diff.progress.drained[i] = *drained ^ old_drained[i];
+        }
+    }
+
+    fn enter(&mut self, level: &Level<N>) {
         self.neighbors.set_bit(level.entrance as usize, true);
     }
 
     #[cfg(feature = "closed-level")]
-    fn visit(&mut self, location: VertexIDType, level: &Level, probe: &ProbeStat) {
+    fn visit(
+        &mut self,
+        location: VertexIDType,
+        choice: Option<ChoiceIDType>,
+        level: &Level<N>,
+        probe: &ProbeStat,
+    ) {
         let loc_idx = location as usize;
-        *self += &probe.diff;
+        probe.apply(&mut self.stat);
+        self.apply_progress(probe);
         let old_memory = self.progress.memory;
         let old_visited = self.progress.visited;
         self.disabled ^= level.neighbors[loc_idx];
@@ -169,7 +251,7 @@ impl Player {
         let mut explore = BitSet::new();
         explore.set_bit(loc_idx, true);
         while explore.any() {
-            let v = explore.first_set() as usize;
+            let v = explore.first_set();
             self.progress.visited.set_bit(v, true);
             self.neighbors |= level.neighbors[v] & !self.disabled;
             let memory_visited = self.progress.memory & self.neighbors;
@@ -181,36 +263,47 @@ impl Player {
         self.neighbors &= !self.progress.visited;
         self.neighbors &= !self.disabled;
         self.diff.location = location;
+        self.diff.choice = choice;
+        if let Some(choice) = choice {
+            self.progress.add_choice(location, choice);
+        }
         self.diff.progress.memory = old_memory ^ self.progress.memory;
         self.diff.progress.visited = old_visited ^ self.progress.visited;
     }
 
     #[cfg(not(feature = "closed-level"))]
-    fn visit(&mut self, location: VertexIDType, level: &Level, probe: &ProbeStat) {
+    fn visit(
+        &mut self,
+        location: VertexIDType,
+        choice: Option<ChoiceIDType>,
+        level: &Level<N>,
+        probe: &ProbeStat,
+    ) {
         let loc_idx = location as usize;
-        *self += &probe.diff;
+        probe.apply(&mut self.stat);
+        self.apply_progress(probe);
         self.progress.visited.set_bit(loc_idx, true);
         self.neighbors |= level.neighbors[loc_idx];
         self.neighbors &= !level.toggle_neighbors[loc_idx];
-        self.neighbors |= self.progress.visited;
+
+        // Rooms are cleared when visited, so they are never neighbors again
+        self.neighbors &= !self.progress.visited;
         self.diff.location = location;
+        self.diff.choice = choice;
+        if let Some(choice) = choice {
+            self.progress.add_choice(location, choice);
+        }
     }
 
-    // TODO support other score functions
-    fn score(&self) -> PlayerScore {
-        let stat = &self.stat;
-        let combat = stat.as_ref();
-        let score = (stat.hp + 1) * 25
-            + (combat.atk as i32 * 5
-                + combat.def as i32 * 3
-                + stat.yk as i32 * 10
-                + stat.gk as i32 * 20
-                + stat.bk as i32 * 30)
-                * 1000;
-        PlayerScore { score }
+    fn score(&self, level: &Level<N>) -> PlayerScore {
+        level.score.score(&self.stat)
     }
 
-    fn print_room_list(writer: &mut dyn Write, level: &Level, list: BitSet) -> io::Result<()> {
+    fn print_room_list(
+        writer: &mut dyn Write,
+        level: &Level<N>,
+        list: BitSet<N>,
+    ) -> io::Result<()> {
         let mut first = true;
         for id in BitSetIter::from(list) {
             if first {
@@ -220,16 +313,20 @@ impl Player {
             }
             write!(writer, "{}", level.vertex_of_id(id).name)?;
         }
-        writeln!(writer, "")
+        writeln!(writer)
     }
 
-    fn print(&self, writer: &mut dyn Write, level: &Level) -> io::Result<()> {
+    fn print(&self, writer: &mut dyn Write, level: &Level<N>) -> io::Result<()> {
         write!(
             writer,
-            "Score: {}\n{{{}}}\nNeighbours: ",
-            self.score(),
-            self
+            "Score: {}\n{{{}, {}",
+            self.score(level),
+            self.stat.display(&level.keys),
+            self.progress
         )?;
+        #[cfg(feature = "closed-level")]
+        write!(writer, ", disabled: {}", self.disabled)?;
+        write!(writer, ", neighbors: {}}}\nNeighbours: ", self.neighbors)?;
         Self::print_room_list(writer, level, self.neighbors)?;
         #[cfg(feature = "closed-level")]
         {
@@ -242,52 +339,27 @@ impl Player {
     }
 }
 
-impl Ge<PlayerStat> for Player {
+impl<const N: usize> Ge<PlayerStat> for Player<N> {
     fn ge(&self, stat: &PlayerStat) -> bool {
         self.stat.ge(stat)
     }
 }
 
-impl AddAssign<&PlayerStat> for Player {
+impl<const N: usize> AddAssign<&PlayerStat> for Player<N> {
     fn add_assign(&mut self, stat: &PlayerStat) {
         self.stat += stat;
     }
 }
 
-impl Display for Player {
-    #[cfg(feature = "closed-level")]
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}, {}, disabled: {}, neighbors: {}",
-            self.stat,
-            self.progress,
-            self.disabled.to_integer(),
-            self.neighbors.to_integer()
-        )
-    }
-
-    #[cfg(not(feature = "closed-level"))]
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}, {}, neighbors: {}",
-            self.stat,
-            self.progress,
-            self.neighbors.to_integer()
-        )
-    }
-}
-
 #[derive(Clone)]
-struct PlayerTrace {
+struct LevelTrace<const N: usize> {
     level_config: i32,
-    level: Rc<Level>,
-    player: Player,
-    trace: Vec<VertexIDType>,
+    level: Rc<Level<N>>,
+    player: Player<N>,
+    trace: Vec<(VertexIDType, ChoiceIDType)>, // Rooms with the index of the result picked
 }
 
-impl PlayerTrace {
+impl<const N: usize> LevelTrace<N> {
     fn new() -> Self {
         Self {
             level_config: 0,
@@ -297,53 +369,76 @@ impl PlayerTrace {
         }
     }
 
-    fn visit(&mut self, location: VertexIDType) {
-        self.player.visit(
-            location,
-            &self.level,
-            &self
-                .level
-                .vertex_of_id(location)
-                .probe(self.player.stat.as_ref()),
-        );
-        self.trace.push(location);
+    // Result of the room, which names the items equipped for its fights
+    fn visit(&mut self, location: VertexIDType, choice: ChoiceIDType) -> ProbeStat {
+        let mut probes = self.level.probe(location, self.player.stat.as_ref());
+        let picked = if probes.len() == 1 {
+            None
+        } else {
+            Some(choice)
+        };
+        let probe = probes.swap_remove(choice as usize);
+        self.player.visit(location, picked, &self.level, &probe);
+        self.trace.push((location, choice));
+        probe
     }
 
-    fn print_room_list(writer: &mut dyn Write, level: &Level, list: &Vec<u8>) -> io::Result<()> {
-        let mut first = true;
-        for id in list {
-            if first {
-                first = false;
-            } else {
-                write!(writer, ", ")?;
-            }
-            write!(writer, "{}", level.vertex_of_id(*id).name)?;
+    // Same trace before visiting any room
+    fn start(&self, init_player: &Player<N>) -> Self {
+        LevelTrace {
+            level_config: self.level_config,
+            level: Rc::clone(&self.level),
+            player: init_player.clone(),
+            trace: Vec::new(),
         }
-        writeln!(writer, "")
     }
 
-    fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+    // Name of the room along with the branches picked and the items equipped in it, if any
+    fn step_name(&self, id: VertexIDType, probe: &ProbeStat) -> String {
+        let room = self.level.vertex_of_id(id);
+        let mut name = if room.choice_count() == 1 {
+            room.name.clone()
+        } else {
+            format!("{} ({})", room.name, room.choice_names()[probe.choice])
+        };
+        let equips = self.level.equip_names(&probe.equips);
+        if !equips.is_empty() {
+            name += &format!(" [equip {}]", equips.join(", "));
+        }
+        name
+    }
+
+    // Name of every room of the trace.
+    // Items depend on the stats when entering the room, so the trace is followed from the start.
+    fn step_names(&self, init_player: &Player<N>) -> Vec<String> {
+        let mut player = self.start(init_player);
+        self.trace
+            .iter()
+            .map(|&(id, choice)| {
+                let probe = player.visit(id, choice);
+                self.step_name(id, &probe)
+            })
+            .collect()
+    }
+
+    fn write(&self, writer: &mut dyn Write, init_player: &Player<N>) -> io::Result<()> {
         self.player.print(writer, &self.level)?;
-        write!(writer, "Trace: ")?;
-        Self::print_room_list(writer, &self.level, &self.trace)
+        writeln!(writer, "Trace: {}", self.step_names(init_player).join(", "))
     }
 
-    fn print(&self, writer: &mut dyn Write, init_player: &Player) -> io::Result<()> {
-        let mut player = PlayerTrace {
-            level_config: self.level_config,
-            level: Rc::clone(&self.level),
-            player: init_player.clone(),
-            trace: Vec::new(),
-        };
+    fn print(&self, writer: &mut dyn Write, init_player: &Player<N>) -> io::Result<()> {
+        let mut player = self.start(init_player);
+        let names = self.step_names(init_player);
 
-        self.write(writer)?;
+        self.write(writer, init_player)?;
         writeln!(
             writer,
             "--------------------------------------------------------------------------------"
         )?;
-        for id in &self.trace {
-            player.visit(*id);
-            player.write(writer)?;
+        for (i, &(id, choice)) in self.trace.iter().enumerate() {
+            player.visit(id, choice);
+            player.player.print(writer, &self.level)?;
+            writeln!(writer, "Trace: {}", names[..=i].join(", "))?;
             writeln!(
                 writer,
                 "--------------------------------------------------------------------------------"
@@ -351,14 +446,41 @@ impl PlayerTrace {
         }
         Ok(())
     }
+
+    // Stats after every room of the trace
+    fn to_data(&self, init_player: &Player<N>) -> PlayerTrace {
+        let mut player = self.start(init_player);
+        let mut steps = Vec::new();
+        for &(id, choice) in &self.trace {
+            let probe = player.visit(id, choice);
+            let room = self.level.vertex_of_id(id);
+            steps.push(TraceStep {
+                room: room.name.clone(),
+                choice: if room.choice_count() == 1 {
+                    None
+                } else {
+                    Some(room.choice_names()[probe.choice].clone())
+                },
+                equip: self.level.equip_names(&probe.equips),
+                stat: player.player.stat.unshifted(),
+            });
+        }
+        PlayerTrace {
+            config: self.level_config,
+            score: self.player.score(&self.level).points(),
+            init_stat: init_player.stat.unshifted(),
+            stat: self.player.stat.unshifted(),
+            trace: steps,
+        }
+    }
 }
 
 // Track pareto frontier of traces by stat
-struct OptimalStatSet {
-    trace: Vec<PlayerTrace>,
+struct OptimalStatSet<const N: usize> {
+    trace: Vec<LevelTrace<N>>,
 }
 
-impl OptimalStatSet {
+impl<const N: usize> OptimalStatSet<N> {
     fn new() -> Self {
         Self { trace: Vec::new() }
     }
@@ -367,9 +489,9 @@ impl OptimalStatSet {
         self.trace.iter().all(|trace| !trace.player.stat.ge(stat))
     }
 
-    fn add(&mut self, trace: PlayerTrace, force: bool) -> bool {
+    fn add(&mut self, trace: LevelTrace<N>, force: bool) -> bool {
         let new_stat = &trace.player.stat;
-        if force || self.addable(&new_stat) {
+        if force || self.addable(new_stat) {
             self.trace.retain(|trace| !new_stat.ge(&trace.player.stat));
             self.trace.push(trace);
             true
@@ -386,15 +508,15 @@ impl OptimalStatSet {
 }
 
 // Track trace with optimal score
-struct OptimalScore {
-    trace: PlayerTrace,
+struct OptimalScore<const N: usize> {
+    trace: LevelTrace<N>,
     score: PlayerScore,
 }
 
-impl OptimalScore {
+impl<const N: usize> OptimalScore<N> {
     fn new() -> Self {
         Self {
-            trace: PlayerTrace::new(),
+            trace: LevelTrace::new(),
             score: PlayerScore::new(),
         }
     }
@@ -413,8 +535,8 @@ impl OptimalScore {
         }
     }
 
-    fn add(&mut self, trace: PlayerTrace, force: bool) -> bool {
-        let score = trace.player.score();
+    fn add(&mut self, trace: LevelTrace<N>, force: bool) -> bool {
+        let score = trace.player.score(&trace.level);
         if force || self.addable(&score) {
             self.trace = trace;
             self.score = score;
@@ -425,7 +547,7 @@ impl OptimalScore {
     }
 
     fn clear(&mut self) {
-        self.trace = PlayerTrace::new();
+        self.trace = LevelTrace::new();
         self.score = PlayerScore::new();
     }
 }
@@ -439,11 +561,11 @@ pub struct SearchConfig {
         default_value = "true",
         parse(try_from_str)
     )]
-    use_estimated_max_combat: bool,
+    pub use_estimated_max_combat: bool,
 
     /// Output new highscores when reaching exit room
     #[structopt(long, default_value = "false", parse(try_from_str))]
-    print_new_highscore: bool,
+    pub print_new_highscore: bool,
 
     /// Calculate scores for the pareto optimal set of stats and keys
     #[structopt(
@@ -452,7 +574,7 @@ pub struct SearchConfig {
         default_value = "false",
         parse(try_from_str)
     )]
-    calculate_optimal_player_by_stat: bool,
+    pub calculate_optimal_player_by_stat: bool,
 
     /// Output optimal scores for each level config
     #[structopt(
@@ -461,7 +583,7 @@ pub struct SearchConfig {
         default_value = "false",
         parse(try_from_str)
     )]
-    print_local_optimal_player_by_score: bool,
+    pub print_local_optimal_player_by_score: bool,
 
     /// Output pareto scores for each level config
     #[structopt(
@@ -470,7 +592,7 @@ pub struct SearchConfig {
         default_value = "false",
         parse(try_from_str)
     )]
-    print_local_optimal_player_by_stat: bool,
+    pub print_local_optimal_player_by_stat: bool,
 
     /// Output optimal score across all level configs
     #[structopt(
@@ -479,7 +601,7 @@ pub struct SearchConfig {
         default_value = "true",
         parse(try_from_str)
     )]
-    print_global_optimal_player_by_score: bool,
+    pub print_global_optimal_player_by_score: bool,
 
     /// Output pareto scores across all level configs
     #[structopt(
@@ -488,7 +610,61 @@ pub struct SearchConfig {
         default_value = "false",
         parse(try_from_str)
     )]
-    print_global_optimal_player_by_stat: bool,
+    pub print_global_optimal_player_by_stat: bool,
+
+    /// Score function replacing the one in the level file (drod or tots)
+    #[structopt(long)]
+    pub score: Option<ScoreFunction>,
+
+    /// Format of the output file (text or json)
+    #[structopt(long, default_value = "text")]
+    pub format: OutputFormat,
+
+    /// Number of threads to search with, or 0 for one per CPU
+    #[structopt(long, default_value = "1")]
+    pub threads: usize,
+
+    /// Checkpoint file to periodically save the search to
+    #[structopt(long, parse(from_os_str))]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Seconds between checkpoints
+    #[structopt(name = "checkpoint_interval", long, default_value = "600")]
+    pub checkpoint_interval: u64,
+}
+
+// Same options as the command line without any arguments
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self::from_iter(&[env!("CARGO_PKG_NAME")])
+    }
+}
+
+impl SearchConfig {
+    fn thread_count(&self) -> usize {
+        match self.threads {
+            0 => thread::available_parallelism().map_or(1, |count| count.get()),
+            threads => threads,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown output format \"{}\"", name)),
+        }
+    }
 }
 
 struct SearchProgress {
@@ -507,42 +683,293 @@ impl SearchProgress {
     }
 }
 
-struct ExtendedProbeStat {
-    location: VertexIDType,
-    probe: ProbeStat,
+// Search the level, continuing from the checkpoint if there is one
+pub fn search(
+    search_config: SearchConfig,
+    level_info: LevelInfo,
+    checkpoint: Option<Checkpoint>,
+    writer: &mut dyn Write,
+    log_writer: &mut dyn Write,
+) -> io::Result<()> {
+    search_with_width(
+        search_config,
+        level_info,
+        checkpoint,
+        writer,
+        log_writer,
+        &mut (),
+    )?;
+    Ok(())
+}
+
+// Search the level without writing anything, reporting events to the callback
+pub fn solve(
+    search_config: SearchConfig,
+    level_info: LevelInfo,
+    callback: &mut dyn SearchCallback,
+) -> io::Result<SearchResult> {
+    search_with_width(
+        search_config,
+        level_info,
+        None,
+        &mut io::sink(),
+        &mut io::sink(),
+        callback,
+    )
+}
+
+// Search with room sets just wide enough for the level
+fn search_with_width(
+    search_config: SearchConfig,
+    level_info: LevelInfo,
+    checkpoint: Option<Checkpoint>,
+    writer: &mut dyn Write,
+    log_writer: &mut dyn Write,
+    callback: &mut dyn SearchCallback,
+) -> io::Result<SearchResult> {
+    match word_count(level_info.room_count) {
+        1 => Search::<1>::new(
+            search_config,
+            level_info,
+            checkpoint,
+            writer,
+            log_writer,
+            callback,
+        )
+        .search(),
+        2 => Search::<2>::new(
+            search_config,
+            level_info,
+            checkpoint,
+            writer,
+            log_writer,
+            callback,
+        )
+        .search(),
+        _ => Search::<4>::new(
+            search_config,
+            level_info,
+            checkpoint,
+            writer,
+            log_writer,
+            callback,
+        )
+        .search(),
+    }
+}
+
+// Writer that counts the bytes written so that a checkpoint can record the output so far
+struct CountingWriter<'a> {
+    writer: &'a mut dyn Write,
+    count: u64,
+}
+
+impl<'a> CountingWriter<'a> {
+    fn new(writer: &'a mut dyn Write) -> Self {
+        Self { writer, count: 0 }
+    }
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Number of situations expanded by threads before their results are merged
+#[cfg(not(feature = "closed-level"))]
+const BATCH_SIZE: usize = 1 << 16;
+
+// Result of reaching a new situation
+enum Expansion<const N: usize> {
+    Exit(Player<N>),
+    Inserted(PlayerProgress<N>),
+    Replaced(PlayerProgress<N>), // Progress of the previous parent
+    Rejected,
+}
+
+// Keep the player with the best objective for each progress
+fn merge_player<const N: usize>(
+    optimal_player: &mut HashMap<PlayerProgress<N>, Player<N>>,
+    new_player: Player<N>,
+) -> Expansion<N> {
+    match optimal_player.entry(new_player.progress.clone()) {
+        Entry::Occupied(mut entry) => {
+            let player = entry.get_mut();
+            let new_objective = new_player.stat.objective();
+            if player.stat.objective().ge(&new_objective) {
+                return Expansion::Rejected;
+            }
+            let old_parent = player.reverted_progress();
+            player.stat.hp = new_objective.hp;
+            player.diff = new_player.diff;
+            Expansion::Replaced(old_parent)
+        }
+        Entry::Vacant(entry) => {
+            let progress = entry.key().clone();
+            entry.insert(new_player);
+            Expansion::Inserted(progress)
+        }
+    }
+}
+
+// Read-only state needed to expand a situation, shared between threads
+#[derive(Clone, Copy)]
+struct Expander<'b, const N: usize> {
+    level: &'b Level<N>,
+    use_estimated_max_combat: bool,
+    max_combat_probe_result: &'b [Vec<ProbeStat>],
+}
+
+impl<'b, const N: usize> Expander<'b, N> {
+    // Probe results of every room, one for each way of making its choices
+    fn probe(&self, combat: &PlayerCombat) -> Vec<Vec<ProbeStat>> {
+        (0..self.level.next_id)
+            .map(|i| self.level.probe(i, combat))
+            .collect()
+    }
+
+    // Players after visiting each room worth visiting next.
+    // A free or priority room is the only one visited when it is available.
+    fn successors(&self, player: &Player<N>, probe_result: &[Vec<ProbeStat>]) -> Vec<Player<N>> {
+        let level = self.level;
+        let mut locations = Vec::with_capacity(player.neighbors.get_weight() as usize);
+        let was_intermediate = if player.diff.location == VertexIDType::MAX {
+            false
+        } else {
+            level
+                .vertex_of_id(player.diff.location)
+                .room_type
+                .contains(RoomType::INTERMEDIATE)
+        };
+
+        for id in BitSetIter::from(player.neighbors) {
+            if was_intermediate
+                && !level.neighbors[player.diff.location as usize].get_bit(id as usize)
+            {
+                continue;
+            }
+            // Each way of making the choices of the room is a separate successor
+            let probes = &probe_result[id as usize];
+            let start = locations.len();
+            for (choice, probe) in probes.iter().enumerate() {
+                if player.ge(&probe.req) && probe.counters(&player.progress.counters).is_some() {
+                    locations.push((id, choice as ChoiceIDType));
+                }
+            }
+            if locations.len() == start {
+                continue;
+            }
+            let probe = &probes[0];
+            let room_type = level.vertex_of_id(id).room_type;
+            let priority = room_type.contains(RoomType::PRIORITY);
+            let intermediate = room_type.contains(RoomType::INTERMEDIATE);
+            #[allow(unused_mut)]
+            let mut free = self.use_estimated_max_combat
+                && probes.len() == 1
+                && id != level.exit
+                && !intermediate
+                && (probe.diff.as_ref().flag & player.stat.as_ref().flag).bits() == 0
+                && !room_type.contains(RoomType::DELAYED)
+                && self.max_combat_probe_result[id as usize][0]
+                    .diff
+                    .objective()
+                    == probe.diff.objective()
+                && probe.diff.nonnegative()
+                // ATK and DEF may be better gained after the fights that drain them
+                && !(level.drains && (probe.diff.as_ref().atk > 0 || probe.diff.as_ref().def > 0))
+                && probe.hp_ops.is_empty()
+                && probe.counter_ops.is_empty();
+
+            #[cfg(feature = "closed-level")]
+            {
+                free = free
+                    && !room_type.contains(RoomType::REPEATED)
+                    && !level.boundary_mask.get_bit(id as usize)
+                    && !level.boundary_mask.get_bit(player.diff.location as usize);
+            }
+            if !free && room_type.contains(RoomType::ONLY_WHEN_FREE) {
+                locations.truncate(start);
+                continue;
+            }
+            if free || priority {
+                locations.drain(..start);
+                break;
+            }
+        }
+
+        locations
+            .into_iter()
+            .map(|(id, choice)| {
+                let mut new_player = player.clone();
+                let probes = &probe_result[id as usize];
+                let picked = if probes.len() == 1 {
+                    None
+                } else {
+                    Some(choice)
+                };
+                new_player.visit(id, picked, level, &probes[choice as usize]);
+                new_player
+            })
+            .collect()
+    }
 }
 
-pub struct Search<'a> {
+// Outcome of a config kept until every config is searched
+struct ConfigSummary<const N: usize> {
+    config: i32,
+    situations: usize,
+    seconds: u64,
+    optimal: OptimalScore<N>,
+    pareto: OptimalStatSet<N>,
+}
+
+pub struct Search<'a, const N: usize> {
     search_config: SearchConfig,
     level_info: LevelInfo,
-    init_player: Player,
-    max_combat_probe_result: Vec<ProbeStat>,
+    init_player: Player<N>,
+    max_combat_probe_result: Vec<Vec<ProbeStat>>,
     search_progress: SearchProgress,
     level_config: i32,
-    level: Rc<Level>,
-    local_optimal_player_by_score: OptimalScore,
-    global_optimal_player_by_score: OptimalScore,
-    local_optimal_player_by_stat: OptimalStatSet,
-    global_optimal_player_by_stat: OptimalStatSet,
-    probe_result: HashMap<PlayerCombat, Vec<ProbeStat>>,
-    player_progress_rc: HashMap<PlayerProgress, i32>,
-    optimal_player: HashMap<PlayerProgress, Player>, // TODO only store objective, diff?
-    clones: VecDeque<PlayerProgress>,
-    writer: &'a mut dyn Write,
+    level: Rc<Level<N>>,
+    local_optimal_player_by_score: OptimalScore<N>,
+    global_optimal_player_by_score: OptimalScore<N>,
+    local_optimal_player_by_stat: OptimalStatSet<N>,
+    global_optimal_player_by_stat: OptimalStatSet<N>,
+    probe_result: HashMap<PlayerCombat, Vec<Vec<ProbeStat>>>,
+    player_progress_rc: HashMap<PlayerProgress<N>, i32>,
+    optimal_player: ShardedMap<PlayerProgress<N>, Player<N>>, // TODO only store objective, diff?
+    clones: VecDeque<PlayerProgress<N>>,
+    config_results: Vec<ConfigSummary<N>>,
+    config_begin: Instant,
+    checkpoint_begin: Instant,
+    resume: Option<Checkpoint>,
+    writer: CountingWriter<'a>,
     log_writer: &'a mut dyn Write,
+    callback: &'a mut dyn SearchCallback,
 }
 
-impl<'a> Search<'a> {
+impl<'a, const N: usize> Search<'a, N> {
     pub fn new(
         search_config: SearchConfig,
         level_info: LevelInfo,
+        checkpoint: Option<Checkpoint>,
         writer: &'a mut dyn Write,
         log_writer: &'a mut dyn Write,
+        callback: &'a mut dyn SearchCallback,
     ) -> Self {
-        let init_player = Player {
+        let mut init_player = Player {
             stat: level_info.init_player(),
             ..Default::default()
         };
+        init_player.progress.counters = level_info.init_counters();
+        let thread_count = search_config.thread_count();
         Self {
             search_config,
             level_info,
@@ -557,25 +984,35 @@ impl<'a> Search<'a> {
             global_optimal_player_by_stat: OptimalStatSet::new(),
             probe_result: HashMap::new(),
             player_progress_rc: HashMap::new(),
-            optimal_player: HashMap::new(),
+            optimal_player: ShardedMap::new(thread_count),
             clones: VecDeque::new(),
-            writer,
+            config_results: Vec::new(),
+            config_begin: Instant::now(),
+            checkpoint_begin: Instant::now(),
+            resume: checkpoint,
+            writer: CountingWriter::new(writer),
             log_writer,
+            callback,
         }
     }
 
-    fn probe(&mut self, combat: &PlayerCombat) -> &Vec<ProbeStat> {
+    fn expander(&self) -> Expander<'_, N> {
+        Expander {
+            level: &self.level,
+            use_estimated_max_combat: self.search_config.use_estimated_max_combat,
+            max_combat_probe_result: &self.max_combat_probe_result,
+        }
+    }
+
+    fn probe(&mut self, combat: &PlayerCombat) -> &Vec<Vec<ProbeStat>> {
         if !self.probe_result.contains_key(combat) {
-            let mut res = Vec::new();
-            for i in 0..self.level.next_id {
-                res.push(self.level.vertex_of_id(i).probe(combat));
-            }
+            let res = self.expander().probe(combat);
             self.probe_result.insert(combat.clone(), res);
         }
         self.probe_result.get(combat).unwrap()
     }
 
-    fn add_exit_player(&mut self, player: &Player) -> io::Result<()> {
+    fn add_exit_player(&mut self, player: &Player<N>) -> io::Result<()> {
         if self.search_config.calculate_optimal_player_by_stat {
             if self.local_optimal_player_by_stat.addable(&player.stat) {
                 let player_trace = self.reconstruct_trace(player);
@@ -585,11 +1022,18 @@ impl<'a> Search<'a> {
             }
         }
 
-        if self.local_optimal_player_by_score.addable(&player.score()) {
+        if self
+            .local_optimal_player_by_score
+            .addable(&player.score(&self.level))
+        {
             let player_trace = self.reconstruct_trace(player);
-            if self.search_config.print_new_highscore {
+            let data = player_trace.to_data(&self.init_player);
+            self.callback.new_highscore(&data);
+            if self.search_config.print_new_highscore
+                && self.search_config.format == OutputFormat::Text
+            {
                 write!(self.writer, "New High ")?;
-                player_trace.write(self.writer)?;
+                player_trace.write(&mut self.writer, &self.init_player)?;
                 writeln!(self.writer, "--------------------------------------------------------------------------------")?;
             }
             self.local_optimal_player_by_score.add(player_trace, true);
@@ -597,7 +1041,7 @@ impl<'a> Search<'a> {
         Ok(())
     }
 
-    fn remove_player_progress(&mut self, progress: PlayerProgress) {
+    fn remove_player_progress(&mut self, progress: PlayerProgress<N>) {
         let mut progress = progress;
         while progress != self.init_player.progress {
             let rc = self
@@ -610,73 +1054,76 @@ impl<'a> Search<'a> {
             }
             let diff = self
                 .optimal_player
-                .get(&progress)
+                .remove(&progress)
                 .expect("optimal_player missing progress")
-                .diff
-                .clone();
-            self.optimal_player.remove_entry(&progress);
+                .diff;
             progress -= &diff;
         }
     }
 
-    fn expand(
-        &mut self,
-        player: &Player,
-        location: VertexIDType,
-        probe: &ProbeStat,
-    ) -> io::Result<()> {
-        let mut new_player = player.clone();
-        new_player.visit(location, &self.level, probe);
+    // Start expanding a situation. Its reference count is the number of kept successors.
+    fn pop_player_progress(&mut self, progress: &PlayerProgress<N>) -> io::Result<()> {
+        self.search_progress.current_search_count += 1;
+        self.print_progress()?;
+        self.player_progress_rc.insert(progress.clone(), 0);
+        Ok(())
+    }
 
-        if location == self.level.exit {
-            self.add_exit_player(&new_player)?;
-            return Ok(());
+    // Finish expanding a situation, removing it when no successor refers to it
+    fn release_player_progress(&mut self, progress: PlayerProgress<N>) {
+        if *self
+            .player_progress_rc
+            .get(&progress)
+            .expect("rc missing progress")
+            == 0
+        {
+            self.remove_player_progress(progress);
         }
+    }
 
-        let new_progress = new_player.progress.clone();
-        if self.optimal_player.contains_key(&new_progress) {
-            let new_objective = new_player.stat.objective();
-            let player = self
-                .optimal_player
-                .get_mut(&new_progress)
-                .expect("optimal_player missing new_progress");
-            if player.stat.objective().ge(&new_objective) {
-                return Ok(());
+    fn record_expansion(
+        &mut self,
+        parent: &PlayerProgress<N>,
+        expansion: Expansion<N>,
+    ) -> io::Result<()> {
+        match expansion {
+            Expansion::Exit(player) => self.add_exit_player(&player)?,
+            Expansion::Inserted(progress) => {
+                *self
+                    .player_progress_rc
+                    .get_mut(parent)
+                    .expect("rc missing parent") += 1;
+                self.clones.push_back(progress);
+                self.search_progress.total_search_count += 1;
             }
-            match self.player_progress_rc.get_mut(&player.progress) {
-                Some(rc) => {
-                    *rc += 1;
-                }
-                None => {
-                    self.player_progress_rc.insert(player.progress.clone(), 1);
-                }
+            Expansion::Replaced(old_parent) => {
+                *self
+                    .player_progress_rc
+                    .get_mut(parent)
+                    .expect("rc missing parent") += 1;
+                self.remove_player_progress(old_parent);
             }
-            player.stat.hp = new_objective.hp;
-            player.diff = new_player.diff;
-            let progress = player.reverted_progress();
-            self.remove_player_progress(progress);
-        } else {
-            self.optimal_player.insert(new_progress.clone(), new_player);
-            match self.player_progress_rc.get_mut(&player.progress) {
-                Some(rc) => {
-                    *rc += 1;
-                }
-                None => {
-                    self.player_progress_rc.insert(player.progress.clone(), 1);
-                }
-            }
-            self.clones.push_back(new_progress);
-            self.search_progress.total_search_count += 1;
+            Expansion::Rejected => {}
         }
         Ok(())
     }
 
-    fn reconstruct_trace(&self, player: &Player) -> PlayerTrace {
+    fn expand(&mut self, parent: &PlayerProgress<N>, new_player: Player<N>) -> io::Result<()> {
+        let expansion = if new_player.diff.location == self.level.exit {
+            Expansion::Exit(new_player)
+        } else {
+            let shard = self.optimal_player.shard_mut(&new_player.progress);
+            merge_player(shard, new_player)
+        };
+        self.record_expansion(parent, expansion)
+    }
+
+    fn reconstruct_trace(&self, player: &Player<N>) -> LevelTrace<N> {
         let mut trace = Vec::new();
         let mut diff = player.diff.clone();
         let mut progress = player.progress.clone();
         while progress != self.init_player.progress {
-            trace.push(diff.location);
+            trace.push((diff.location, diff.choice.unwrap_or(0)));
             progress -= &diff;
             diff = self
                 .optimal_player
@@ -686,7 +1133,7 @@ impl<'a> Search<'a> {
                 .clone();
         }
         trace.reverse();
-        PlayerTrace {
+        LevelTrace {
             level_config: self.level_config,
             level: Rc::clone(&self.level),
             player: player.clone(),
@@ -694,28 +1141,41 @@ impl<'a> Search<'a> {
         }
     }
 
-    fn estimate_max_combat(&mut self) -> io::Result<()> {
-        let mut max_combat = PlayerCombat::with_stat(i16::MAX, i16::MAX);
+    fn estimate_max_combat(&mut self, print: bool) -> io::Result<()> {
+        // Leave headroom so that stat boosts in rooms do not overflow
+        let mut max_combat = PlayerCombat::with_stat(i16::MAX / 2, i16::MAX / 2);
         let mut stat = PlayerStat::default();
         for i in 0..self.level.next_id {
-            let probe = self.level.vertex_of_id(i).probe(&max_combat);
             let mut diff = PlayerStat::default();
-            diff.join(probe.diff);
+            for probe in self.level.probe(i, &max_combat) {
+                diff.join(probe.diff);
+            }
             stat += &diff;
         }
         max_combat = self.init_player.stat.as_ref().clone();
         max_combat += stat.as_ref();
-        write!(self.writer, "Estimated {}", max_combat)?;
-        write!(self.log_writer, "Estimated {}", max_combat)?;
+        if print {
+            if self.search_config.format == OutputFormat::Text {
+                write!(self.writer, "Estimated {}", max_combat)?;
+            }
+            write!(self.log_writer, "Estimated {}", max_combat)?;
+        }
         self.max_combat_probe_result = self.probe(&max_combat).clone();
         Ok(())
     }
 
     fn print_progress(&mut self) -> io::Result<()> {
-        if self.search_progress.current_search_count % 1000000 == 0
+        if self
+            .search_progress
+            .current_search_count
+            .is_multiple_of(1000000)
             && self.search_progress.timer_begin.elapsed().as_secs() > 10
         {
             self.search_progress.timer_begin = Instant::now();
+            self.callback.progress(
+                self.search_progress.current_search_count,
+                self.search_progress.total_search_count,
+            );
             writeln!(
                 self.log_writer,
                 "Progress: {}m / {}m",
@@ -726,7 +1186,141 @@ impl<'a> Search<'a> {
         Ok(())
     }
 
-    fn search_config(&mut self, config: i32) -> io::Result<()> {
+    fn search_sequential(&mut self) -> io::Result<()> {
+        while let Some(progress) = self.clones.pop_front() {
+            self.pop_player_progress(&progress)?;
+            let player = self
+                .optimal_player
+                .get(&progress)
+                .expect("optimal_player missing progress")
+                .clone();
+            self.probe(player.stat.as_ref());
+            let probe_result = &self.probe_result[player.stat.as_ref()];
+            let successors = self.expander().successors(&player, probe_result);
+            for new_player in successors {
+                self.expand(&progress, new_player)?;
+            }
+            self.release_player_progress(progress);
+            self.save_checkpoint_if_due()?;
+        }
+        Ok(())
+    }
+
+    // Every successor of a situation visits one more room, so the queue holds all situations
+    // with the same number of visited rooms before any with more. Each such level is expanded
+    // by threads in batches, then merged in queue order so that the result is the same as the
+    // sequential search.
+    #[cfg(not(feature = "closed-level"))]
+    fn search_parallel(&mut self) -> io::Result<()> {
+        let thread_count = self.search_config.thread_count();
+        let mut probe_results: Vec<HashMap<PlayerCombat, Vec<Vec<ProbeStat>>>> =
+            (0..thread_count).map(|_| HashMap::new()).collect();
+        while let Some(first) = self.clones.front() {
+            // The level may already be partly searched when resuming from a checkpoint
+            let visited_count = first.visited.get_weight();
+            let mut remaining = self
+                .clones
+                .iter()
+                .take_while(|progress| progress.visited.get_weight() == visited_count)
+                .count();
+            while remaining > 0 {
+                let batch: Vec<_> = self.clones.drain(..remaining.min(BATCH_SIZE)).collect();
+                remaining -= batch.len();
+
+                // Expand situations of the batch in parallel
+                let expander = self.expander();
+                let optimal_player = &self.optimal_player;
+                let chunk_size = batch.len().div_ceil(thread_count);
+                let successors: Vec<Vec<Player<N>>> = thread::scope(|scope| {
+                    let handles: Vec<_> = batch
+                        .chunks(chunk_size)
+                        .zip(probe_results.iter_mut())
+                        .map(|(chunk, probe_result)| {
+                            scope.spawn(move || {
+                                chunk
+                                    .iter()
+                                    .map(|progress| {
+                                        let player = optimal_player
+                                            .get(progress)
+                                            .expect("optimal_player missing progress");
+                                        let combat = player.stat.as_ref();
+                                        if !probe_result.contains_key(combat) {
+                                            probe_result
+                                                .insert(combat.clone(), expander.probe(combat));
+                                        }
+                                        expander.successors(player, &probe_result[combat])
+                                    })
+                                    .collect::<Vec<_>>()
+                            })
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .flat_map(|handle| handle.join().expect("search thread panicked"))
+                        .collect()
+                });
+
+                // Merge successors into the shard of their progress in parallel,
+                // keeping the order of the queue within each shard
+                let mut expansions = Vec::new();
+                let mut ends = Vec::with_capacity(batch.len());
+                let mut shard_players: Vec<Vec<(usize, Player<N>)>> =
+                    (0..thread_count).map(|_| Vec::new()).collect();
+                for new_players in successors {
+                    for new_player in new_players {
+                        if new_player.diff.location == self.level.exit {
+                            expansions.push(Some(Expansion::Exit(new_player)));
+                        } else {
+                            let shard = self.optimal_player.shard_index(&new_player.progress);
+                            shard_players[shard].push((expansions.len(), new_player));
+                            expansions.push(None);
+                        }
+                    }
+                    ends.push(expansions.len());
+                }
+                let merged: Vec<(usize, Expansion<N>)> = thread::scope(|scope| {
+                    let handles: Vec<_> = self
+                        .optimal_player
+                        .shards_mut()
+                        .zip(shard_players)
+                        .map(|(shard, new_players)| {
+                            scope.spawn(move || {
+                                new_players
+                                    .into_iter()
+                                    .map(|(i, new_player)| (i, merge_player(shard, new_player)))
+                                    .collect::<Vec<_>>()
+                            })
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .flat_map(|handle| handle.join().expect("search thread panicked"))
+                        .collect()
+                });
+                for (i, expansion) in merged {
+                    expansions[i] = Some(expansion);
+                }
+
+                // Record expansions in queue order
+                let mut expansions = expansions.into_iter();
+                let mut begin = 0;
+                for (progress, end) in batch.into_iter().zip(ends) {
+                    self.pop_player_progress(&progress)?;
+                    for expansion in expansions.by_ref().take(end - begin) {
+                        let expansion = expansion.expect("expansion was not merged");
+                        self.record_expansion(&progress, expansion)?;
+                    }
+                    begin = end;
+                    self.release_player_progress(progress);
+                }
+                self.save_checkpoint_if_due()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Build the level of a config and clear the state of the previous one
+    fn load_config(&mut self, config: i32, print: bool) -> io::Result<()> {
         self.search_progress = SearchProgress::new();
         self.local_optimal_player_by_score.clear();
         self.local_optimal_player_by_stat.trace.clear();
@@ -735,109 +1329,46 @@ impl<'a> Search<'a> {
         self.optimal_player.clear();
 
         self.level_config = config;
-        self.level = Rc::new(self.level_info.build(config));
-        // TODO check for errors when building level
-
-        let mut player = self.init_player.clone();
-        player.enter(&self.level);
-        let player_progress = player.progress.clone();
-        self.optimal_player.insert(player_progress.clone(), player);
-        self.clones.push_back(player_progress);
-        self.search_progress.total_search_count += 1;
-
+        self.level = self.load_level(config)?;
         if self.search_config.use_estimated_max_combat {
-            self.estimate_max_combat()?;
+            self.estimate_max_combat(print)?;
         }
+        Ok(())
+    }
 
-        while let Some(progress) = self.clones.pop_front() {
-            self.search_progress.current_search_count += 1;
-            self.print_progress()?;
+    fn load_level(&self, config: i32) -> io::Result<Rc<Level<N>>> {
+        let mut level = self.level_info.build(config)?;
+        if let Some(score) = &self.search_config.score {
+            level.score = score.clone();
+        }
+        Ok(Rc::new(level))
+    }
 
-            let player = self
-                .optimal_player
-                .get(&progress)
-                .expect("optimal_player missing progress")
-                .clone();
-            self.player_progress_rc.insert(progress.clone(), 0);
+    fn search_config(&mut self, config: i32, checkpoint: Option<Checkpoint>) -> io::Result<()> {
+        self.load_config(config, checkpoint.is_none())?;
+        match checkpoint {
+            Some(checkpoint) => self.load_config_state(&checkpoint)?,
+            None => {
+                let mut player = self.init_player.clone();
+                player.enter(&self.level);
+                let player_progress = player.progress.clone();
+                self.optimal_player.insert(player_progress.clone(), player);
+                self.clones.push_back(player_progress);
+                self.search_progress.total_search_count += 1;
+            }
+        }
 
-            let probe_result = self.probe(player.stat.as_ref()).clone();
-            let mut extended_probe_result =
-                Vec::with_capacity(player.neighbors.get_weight() as usize);
-            let was_intermediate = if player.diff.location == u8::MAX {
-                false
+        #[cfg(not(feature = "closed-level"))]
+        {
+            if self.search_config.thread_count() > 1 {
+                self.search_parallel()?;
             } else {
-                self.level
-                    .vertex_of_id(player.diff.location)
-                    .room_type
-                    .contains(RoomType::INTERMEDIATE)
-            };
-
-            let mut has_free_priority = false;
-            for id in BitSetIter::from(player.neighbors) {
-                if was_intermediate
-                    && !self.level.neighbors[player.diff.location as usize].get_bit(id as usize)
-                {
-                    continue;
-                }
-                let probe = &probe_result[id as usize];
-                if !player.ge(&probe.req) {
-                    continue;
-                }
-                let room_type = self.level.vertex_of_id(id).room_type;
-                let priority = room_type.contains(RoomType::PRIORITY);
-                let intermediate = room_type.contains(RoomType::INTERMEDIATE);
-                let mut free = self.search_config.use_estimated_max_combat
-                    && id != self.level.exit
-                    && !intermediate
-                    && (probe.diff.as_ref().flag & player.stat.as_ref().flag).bits() == 0
-                    && !room_type.contains(RoomType::DELAYED)
-                    && self.max_combat_probe_result[id as usize].diff.objective()
-                        == probe.diff.objective()
-                    && probe.diff.nonnegative();
-
-                #[cfg(feature = "closed-level")]
-                {
-                    free = free
-                        && !room_type.contains(RoomType::REPEATED)
-                        && !self.level.boundary_mask.get_bit(id as usize)
-                        && !self
-                            .level
-                            .boundary_mask
-                            .get_bit(player.diff.location as usize);
-                }
-                if !free
-                    && self
-                        .level
-                        .vertex_of_id(id)
-                        .room_type
-                        .contains(RoomType::ONLY_WHEN_FREE)
-                {
-                    continue;
-                }
-                if free || priority {
-                    has_free_priority = true;
-                    self.expand(&player, id, &probe)?;
-                    break;
-                }
-                extended_probe_result.push(ExtendedProbeStat {
-                    location: id,
-                    probe: probe.clone(),
-                })
-            }
-            if !has_free_priority {
-                for probe in extended_probe_result {
-                    self.expand(&player, probe.location, &probe.probe)?;
-                }
-            }
-            if *self
-                .player_progress_rc
-                .get(&progress)
-                .expect("rc missing progress")
-                == 0
-            {
-                self.remove_player_progress(progress);
+                self.search_sequential()?;
             }
         }
+        #[cfg(feature = "closed-level")]
+        self.search_sequential()?;
+
         self.global_optimal_player_by_score
             .add_all(&self.local_optimal_player_by_score);
         if self.search_config.calculate_optimal_player_by_stat {
@@ -847,128 +1378,81 @@ impl<'a> Search<'a> {
         Ok(())
     }
 
-    pub fn search(&mut self) -> io::Result<()> {
-        for config in 0..self.level_info.max_config_number {
-            writeln!(self.log_writer, "Config:")?;
-            self.level_info.print_config(self.log_writer, config);
-
-            writeln!(
-                self.writer,
-                "================================================================================\n\
-                 Config:"
-            )?;
-            self.level_info.print_config(self.writer, config);
-            self.writer.flush()?;
-
-            let begin = Instant::now();
-            self.search_config(config)?;
-
-            let elapsed_secs = begin.elapsed().as_secs();
-            writeln!(
-                self.log_writer,
-                "There are {} situations searched.\n\
-                 Finished searching in {} seconds.",
-                self.search_progress.total_search_count, elapsed_secs
-            )?;
-
-            writeln!(
-                self.writer,
-                "================================================================================\n\
-                 There are {} situations searched.\n\
-                 Finished searching in {} seconds.",
-                 self.search_progress.total_search_count,
-                 elapsed_secs
-            )?;
-
-            if self.search_config.print_local_optimal_player_by_score {
-                if self.local_optimal_player_by_score.score.score > 0 {
-                    writeln!(
-                        self.writer,
-                        "The local optimal player by score is: \n\
-                        --------------------------------------------------------------------------------"
-                    )?;
-                    self.local_optimal_player_by_score
-                        .trace
-                        .print(self.writer, &self.init_player)?;
-                } else {
-                    writeln!(
-                        self.writer,
-                        "It is impossible to reach exit with this config.\n\
-                        ================================================================================"
-                    )?;
-                }
-            }
+    fn write_config_text(&mut self, elapsed_secs: u64) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "================================================================================\n\
+             There are {} situations searched.\n\
+             Finished searching in {} seconds.",
+            self.search_progress.total_search_count, elapsed_secs
+        )?;
 
-            if self.search_config.print_local_optimal_player_by_stat {
+        if self.search_config.print_local_optimal_player_by_score {
+            if self.local_optimal_player_by_score.score.score > 0 {
                 writeln!(
                     self.writer,
-                    "================================================================================\n\
-                     There are {} local optimal players by stats.\n\
-                     ================================================================================\n\
-                     --------------------------------------------------------------------------------",
-                     self.local_optimal_player_by_stat.trace.len()
+                    "The local optimal player by score is: \n\
+                    --------------------------------------------------------------------------------"
                 )?;
-
-                for (i, trace) in self.local_optimal_player_by_stat.trace.iter().enumerate() {
-                    write!(self.writer, "Local optimal player by score [{}] ", i + 1,)?;
-                    trace.write(self.writer)?;
-                    writeln!(
-                        self.writer,
-                        "--------------------------------------------------------------------------------"
-                    )?;
-                }
+                self.local_optimal_player_by_score
+                    .trace
+                    .print(&mut self.writer, &self.init_player)?;
+            } else {
                 writeln!(
                     self.writer,
-                    "================================================================================"
+                    "It is impossible to reach exit with this config.\n\
+                    ================================================================================"
                 )?;
             }
-
-            self.writer.flush()?;
         }
 
-        if self.search_config.print_global_optimal_player_by_score {
+        if self.search_config.print_local_optimal_player_by_stat {
             writeln!(
-                self.log_writer,
-                "--------------------------------------------------------------------------------\n\
-                The global optimal player by score is: "
+                self.writer,
+                "================================================================================\n\
+                 There are {} local optimal players by stats.\n\
+                 ================================================================================\n\
+                 --------------------------------------------------------------------------------",
+                 self.local_optimal_player_by_stat.trace.len()
             )?;
-            self.level_info.print_config(
-                self.log_writer,
-                self.global_optimal_player_by_score.trace.level_config,
-            );
-            self.global_optimal_player_by_score
-                .trace
-                .write(self.log_writer)?;
+
+            for (i, trace) in self.local_optimal_player_by_stat.trace.iter().enumerate() {
+                write!(self.writer, "Local optimal player by score [{}] ", i + 1,)?;
+                trace.write(&mut self.writer, &self.init_player)?;
+                writeln!(
+                    self.writer,
+                    "--------------------------------------------------------------------------------"
+                )?;
+            }
             writeln!(
-                self.log_writer,
-                "--------------------------------------------------------------------------------"
+                self.writer,
+                "================================================================================"
             )?;
+        }
+        Ok(())
+    }
 
+    fn write_global_text(&mut self) -> io::Result<()> {
+        if self.search_config.print_global_optimal_player_by_score {
             writeln!(
                 self.writer,
                 "////////////////////////////////////////////////////////////////////////////////\n\
                 The global optimal player by score is: "
             )?;
             self.level_info.print_config(
-                self.writer,
+                &mut self.writer,
                 self.global_optimal_player_by_score.trace.level_config,
-            );
+            )?;
             writeln!(
                 self.writer,
                 "--------------------------------------------------------------------------------"
             )?;
             self.global_optimal_player_by_score
                 .trace
-                .print(self.writer, &self.init_player)?;
+                .print(&mut self.writer, &self.init_player)?;
         }
 
         if self.search_config.print_global_optimal_player_by_stat {
-            writeln!(
-                self.log_writer,
-                "There are {} global optimal players by stat.",
-                self.global_optimal_player_by_stat.trace.len()
-            )?;
-
             writeln!(
                 self.writer,
                 "////////////////////////////////////////////////////////////////////////////////\n\
@@ -980,8 +1464,8 @@ impl<'a> Search<'a> {
             for (i, trace) in self.global_optimal_player_by_stat.trace.iter().enumerate() {
                 write!(self.writer, "Global optimal player by score [{}] ", i + 1,)?;
                 self.level_info
-                    .print_config(self.writer, trace.level_config);
-                trace.write(self.writer)?;
+                    .print_config(&mut self.writer, trace.level_config)?;
+                trace.write(&mut self.writer, &self.init_player)?;
                 writeln!(
                     self.writer,
                     "--------------------------------------------------------------------------------"
@@ -992,7 +1476,157 @@ impl<'a> Search<'a> {
                 "////////////////////////////////////////////////////////////////////////////////"
             )?;
         }
-        self.writer.flush()?;
         Ok(())
     }
+
+    // Optimal trace, or none when the exit cannot be reached
+    fn optimal_data(&self, optimal: &OptimalScore<N>) -> Option<PlayerTrace> {
+        if optimal.score.score > 0 {
+            Some(optimal.trace.to_data(&self.init_player))
+        } else {
+            None
+        }
+    }
+
+    // Pareto set, or none when it is not calculated
+    fn pareto_data(&self, pareto: &OptimalStatSet<N>) -> Option<Vec<PlayerTrace>> {
+        if self.search_config.calculate_optimal_player_by_stat {
+            Some(
+                pareto
+                    .trace
+                    .iter()
+                    .map(|trace| trace.to_data(&self.init_player))
+                    .collect(),
+            )
+        } else {
+            None
+        }
+    }
+
+    fn config_data(&self, summary: &ConfigSummary<N>) -> ConfigResult {
+        ConfigResult {
+            config: summary.config,
+            situations: summary.situations,
+            seconds: summary.seconds,
+            optimal: self.optimal_data(&summary.optimal),
+            pareto: self.pareto_data(&summary.pareto),
+        }
+    }
+
+    pub fn search(&mut self) -> io::Result<SearchResult> {
+        #[cfg(feature = "closed-level")]
+        {
+            if self.search_config.thread_count() > 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "parallel search is not supported for closed levels",
+                ));
+            }
+        }
+        let text = self.search_config.format == OutputFormat::Text;
+        let mut checkpoint = self.resume.take();
+        let first_config = match &checkpoint {
+            Some(checkpoint) => self.load_global_state(checkpoint)?,
+            None => 0,
+        };
+        for config in first_config..self.level_info.max_config_number {
+            writeln!(self.log_writer, "Config:")?;
+            self.level_info.print_config(self.log_writer, config)?;
+
+            let checkpoint = checkpoint.take();
+            match &checkpoint {
+                Some(checkpoint) => {
+                    writeln!(self.log_writer, "Resuming from checkpoint.")?;
+                    self.config_begin = checkpoint.config_begin()?;
+                }
+                None => {
+                    if text {
+                        writeln!(
+                            self.writer,
+                            "================================================================================\n\
+                             Config:"
+                        )?;
+                        self.level_info.print_config(&mut self.writer, config)?;
+                        self.writer.flush()?;
+                    }
+                    self.config_begin = Instant::now();
+                }
+            }
+
+            self.search_config(config, checkpoint)?;
+
+            let elapsed_secs = self.config_begin.elapsed().as_secs();
+            writeln!(
+                self.log_writer,
+                "There are {} situations searched.\n\
+                 Finished searching in {} seconds.",
+                self.search_progress.total_search_count, elapsed_secs
+            )?;
+
+            if text {
+                self.write_config_text(elapsed_secs)?;
+            }
+            self.writer.flush()?;
+
+            let summary = ConfigSummary {
+                config,
+                situations: self.search_progress.total_search_count,
+                seconds: elapsed_secs,
+                optimal: mem::replace(&mut self.local_optimal_player_by_score, OptimalScore::new()),
+                pareto: mem::replace(
+                    &mut self.local_optimal_player_by_stat,
+                    OptimalStatSet::new(),
+                ),
+            };
+            let result = self.config_data(&summary);
+            self.callback.config_finished(&result);
+            self.config_results.push(summary);
+        }
+
+        if self.search_config.print_global_optimal_player_by_score {
+            writeln!(
+                self.log_writer,
+                "--------------------------------------------------------------------------------\n\
+                The global optimal player by score is: "
+            )?;
+            self.level_info.print_config(
+                self.log_writer,
+                self.global_optimal_player_by_score.trace.level_config,
+            )?;
+            self.global_optimal_player_by_score
+                .trace
+                .write(self.log_writer, &self.init_player)?;
+            writeln!(
+                self.log_writer,
+                "--------------------------------------------------------------------------------"
+            )?;
+        }
+
+        if self.search_config.print_global_optimal_player_by_stat {
+            writeln!(
+                self.log_writer,
+                "There are {} global optimal players by stat.",
+                self.global_optimal_player_by_stat.trace.len()
+            )?;
+        }
+
+        let result = SearchResult {
+            configs: self
+                .config_results
+                .iter()
+                .map(|summary| self.config_data(summary))
+                .collect(),
+            optimal: self.optimal_data(&self.global_optimal_player_by_score),
+            pareto: self.pareto_data(&self.global_optimal_player_by_stat),
+        };
+        if text {
+            self.write_global_text()?;
+        } else {
+            let document = result.to_json(&self.level_info);
+            serde_json::to_writer_pretty(&mut self.writer, &document)?;
+            writeln!(self.writer)?;
+        }
+        self.writer.flush()?;
+        Ok(result)
+    }
 }
@@ -0,0 +1,446 @@
+use super::{
+    ConfigSummary, LevelTrace, OptimalScore, OptimalStatSet, OutputFormat, Player, PlayerProgress,
+    PlayerProgressDiff, Search,
+};
+use crate::drod::assets::Catalog;
+use crate::drod::bitset::BitSet;
+use crate::drod::level_info::Parser;
+use crate::drod::model::{PlayerStat, ScoreFunction};
+use crate::drod::{ChoiceIDType, VertexIDType};
+
+use serde_json::{json, Value};
+
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const VERSION: u64 = 8;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid checkpoint: {}", message),
+    )
+}
+
+fn field<'v>(value: &'v Value, key: &str) -> io::Result<&'v Value> {
+    value
+        .get(key)
+        .ok_or_else(|| invalid(&format!("missing field \"{}\"", key)))
+}
+
+fn int_field<T: TryFrom<u64>>(value: &Value, key: &str) -> io::Result<T> {
+    field(value, key)?
+        .as_u64()
+        .and_then(|int| T::try_from(int).ok())
+        .ok_or_else(|| invalid(&format!("field \"{}\" is not a valid integer", key)))
+}
+
+fn array_field<'v>(value: &'v Value, key: &str) -> io::Result<&'v Vec<Value>> {
+    field(value, key)?
+        .as_array()
+        .ok_or_else(|| invalid(&format!("field \"{}\" is not an array", key)))
+}
+
+fn bitset_to_json<const N: usize>(bitset: &BitSet<N>) -> Value {
+    json!(bitset.words()[..])
+}
+
+fn bitset_from_json<const N: usize>(value: &Value, key: &str) -> io::Result<BitSet<N>> {
+    let mut words = [0; N];
+    let array = array_field(value, key)?;
+    if array.len() != N {
+        return Err(invalid(&format!("field \"{}\" has the wrong size", key)));
+    }
+    for (word, value) in words.iter_mut().zip(array) {
+        *word = value
+            .as_u64()
+            .ok_or_else(|| invalid(&format!("field \"{}\" is not a bitset", key)))?;
+    }
+    Ok(BitSet::from_words(words))
+}
+
+fn ints_from_json<T: Copy + Default + TryFrom<i64>, const L: usize>(
+    value: &Value,
+    key: &str,
+) -> io::Result<[T; L]> {
+    let mut ints = [T::default(); L];
+    let array = array_field(value, key)?;
+    if array.len() != L {
+        return Err(invalid(&format!("field \"{}\" has the wrong size", key)));
+    }
+    for (int, value) in ints.iter_mut().zip(array) {
+        *int = value
+            .as_i64()
+            .and_then(|int| T::try_from(int).ok())
+            .ok_or_else(|| invalid(&format!("field \"{}\" is not an integer list", key)))?;
+    }
+    Ok(ints)
+}
+
+// Rooms along with the branches picked in them
+fn steps_from_json(value: &Value, key: &str) -> io::Result<Vec<(VertexIDType, ChoiceIDType)>> {
+    let mut steps = Vec::new();
+    for step in array_field(value, key)? {
+        let step = match step.as_array() {
+            Some(step) if step.len() == 2 => step,
+            _ => return Err(invalid(&format!("field \"{}\" has an invalid step", key))),
+        };
+        let id = step[0]
+            .as_u64()
+            .and_then(|id| VertexIDType::try_from(id).ok())
+            .ok_or_else(|| invalid(&format!("field \"{}\" has an invalid room", key)))?;
+        let choice = step[1]
+            .as_u64()
+            .and_then(|choice| ChoiceIDType::try_from(choice).ok())
+            .ok_or_else(|| invalid(&format!("field \"{}\" has an invalid choice", key)))?;
+        steps.push((id, choice));
+    }
+    Ok(steps)
+}
+
+fn score_to_json(score: &ScoreFunction) -> Value {
+    json!({
+        "hp": score.hp,
+        "atk": score.atk,
+        "def": score.def,
+        "equip_atk": score.equip_atk,
+        "equip_def": score.equip_def,
+        "gr": score.gr,
+        "rep": score.rep,
+        "keys": score.keys[..],
+        "divisor": score.divisor,
+    })
+}
+
+fn stat_from_json(value: &Value, keys: &[String]) -> io::Result<PlayerStat> {
+    let mut parser = Parser::new(Catalog::default()).with_keys(keys);
+    let mut stat = parser.player_stat(value, "$.stat");
+    stat.hp -= 1;
+    parser
+        .finish(stat)
+        .map_err(|errors| invalid(&errors.0[0].to_string()))
+}
+
+impl<const N: usize> PlayerProgress<N> {
+    fn to_json(&self) -> Value {
+        #[allow(unused_mut)]
+        let mut value = json!({
+            "visited": bitset_to_json(&self.visited),
+            "counters": self.counters[..],
+            "choices": self.choices(),
+            "drained": self.drained[..],
+        });
+        #[cfg(feature = "closed-level")]
+        {
+            value["memory"] = bitset_to_json(&self.memory);
+        }
+        value
+    }
+
+    fn from_json(value: &Value) -> io::Result<Self> {
+        Ok(Self {
+            visited: bitset_from_json(value, "visited")?,
+            counters: ints_from_json(value, "counters")?,
+            choices: Some(Arc::new(steps_from_json(value, "choices")?))
+                .filter(|choices| !choices.is_empty()),
+            drained: ints_from_json(value, "drained")?,
+
+            #[cfg(feature = "closed-level")]
+            memory: bitset_from_json(value, "memory")?,
+        })
+    }
+}
+
+impl<const N: usize> Player<N> {
+    fn to_json(&self, keys: &[String]) -> Value {
+        #[allow(unused_mut)]
+        let mut value = json!({
+            "stat": self.stat.unshifted().to_json(keys),
+            "items": self.stat.combat.items,
+            "progress": self.progress.to_json(),
+            "diff": self.diff.progress.to_json(),
+            "location": self.diff.location,
+            "choice": self.diff.choice,
+            "neighbors": bitset_to_json(&self.neighbors),
+        });
+        #[cfg(feature = "closed-level")]
+        {
+            value["disabled"] = bitset_to_json(&self.disabled);
+        }
+        value
+    }
+
+    fn from_json(value: &Value, keys: &[String]) -> io::Result<Self> {
+        // Owned items are not part of the stats of a level file
+        let mut stat = stat_from_json(field(value, "stat")?, keys)?;
+        stat.combat.items = int_field(value, "items")?;
+        Ok(Self {
+            stat,
+            progress: PlayerProgress::from_json(field(value, "progress")?)?,
+            diff: PlayerProgressDiff {
+                progress: PlayerProgress::from_json(field(value, "diff")?)?,
+                location: int_field(value, "location")?,
+                choice: match field(value, "choice")? {
+                    Value::Null => None,
+                    _ => Some(int_field(value, "choice")?),
+                },
+            },
+            neighbors: bitset_from_json(value, "neighbors")?,
+
+            #[cfg(feature = "closed-level")]
+            disabled: bitset_from_json(value, "disabled")?,
+        })
+    }
+}
+
+// Search state saved to a file so that an interrupted search can be continued
+pub struct Checkpoint {
+    data: Value,
+}
+
+impl Checkpoint {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data: Value = serde_json::from_slice(&fs::read(path)?)?;
+        if int_field::<u64>(&data, "version")? != VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        Ok(Self { data })
+    }
+
+    // Length of the output file when the checkpoint was saved
+    pub fn output_length(&self) -> io::Result<u64> {
+        int_field(&self.data, "output_length")
+    }
+
+    // Time the current config would have started at to have taken as long as before
+    pub(super) fn config_begin(&self) -> io::Result<Instant> {
+        let seconds = int_field(&self.data, "seconds")?;
+        Ok(Instant::now()
+            .checked_sub(Duration::from_secs(seconds))
+            .unwrap_or_else(Instant::now))
+    }
+}
+
+impl<'a, const N: usize> Search<'a, N> {
+    // Options that change the result of the search
+    fn options_to_json(&self) -> Value {
+        let format = match self.search_config.format {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+        };
+        json!({
+            "use_max_combat": self.search_config.use_estimated_max_combat,
+            "calculate_by_stat": self.search_config.calculate_optimal_player_by_stat,
+            "score": self.search_config.score.as_ref().map(score_to_json),
+            "format": format,
+        })
+    }
+
+    fn trace_to_json(&self, trace: &LevelTrace<N>) -> Value {
+        json!({
+            "config": trace.level_config,
+            "player": trace.player.to_json(self.level_info.keys()),
+            "trace": trace.trace,
+        })
+    }
+
+    fn trace_from_json(&self, value: &Value) -> io::Result<LevelTrace<N>> {
+        let level_config = int_field(value, "config")?;
+        if level_config >= self.level_info.max_config_number {
+            return Err(invalid("config is out of range"));
+        }
+        Ok(LevelTrace {
+            level_config,
+            level: self.load_level(level_config)?,
+            player: Player::from_json(field(value, "player")?, self.level_info.keys())?,
+            trace: steps_from_json(value, "trace")?,
+        })
+    }
+
+    fn optimal_score_to_json(&self, optimal: &OptimalScore<N>) -> Value {
+        if optimal.score.score > 0 {
+            self.trace_to_json(&optimal.trace)
+        } else {
+            Value::Null
+        }
+    }
+
+    fn optimal_score_from_json(&self, value: &Value) -> io::Result<OptimalScore<N>> {
+        let mut optimal = OptimalScore::new();
+        if !value.is_null() {
+            optimal.add(self.trace_from_json(value)?, true);
+        }
+        Ok(optimal)
+    }
+
+    fn optimal_stat_to_json(&self, optimal: &OptimalStatSet<N>) -> Value {
+        optimal
+            .trace
+            .iter()
+            .map(|trace| self.trace_to_json(trace))
+            .collect()
+    }
+
+    fn optimal_stat_from_json(&self, value: &Value, key: &str) -> io::Result<OptimalStatSet<N>> {
+        let mut optimal = OptimalStatSet::new();
+        for trace in array_field(value, key)? {
+            optimal.add(self.trace_from_json(trace)?, true);
+        }
+        Ok(optimal)
+    }
+
+    fn summary_to_json(&self, summary: &ConfigSummary<N>) -> Value {
+        json!({
+            "config": summary.config,
+            "situations": summary.situations,
+            "seconds": summary.seconds,
+            "optimal": self.optimal_score_to_json(&summary.optimal),
+            "pareto": self.optimal_stat_to_json(&summary.pareto),
+        })
+    }
+
+    fn summary_from_json(&self, value: &Value) -> io::Result<ConfigSummary<N>> {
+        Ok(ConfigSummary {
+            config: int_field(value, "config")?,
+            situations: int_field(value, "situations")?,
+            seconds: int_field(value, "seconds")?,
+            optimal: self.optimal_score_from_json(field(value, "optimal")?)?,
+            pareto: self.optimal_stat_from_json(value, "pareto")?,
+        })
+    }
+
+    pub(super) fn save_checkpoint_if_due(&mut self) -> io::Result<()> {
+        let interval = Duration::from_secs(self.search_config.checkpoint_interval);
+        if self.search_config.checkpoint.is_some() && self.checkpoint_begin.elapsed() >= interval {
+            self.save_checkpoint()?;
+            self.checkpoint_begin = Instant::now();
+        }
+        Ok(())
+    }
+
+    // Write to a temporary file first so that a crash never leaves a partial checkpoint
+    fn save_checkpoint(&mut self) -> io::Result<()> {
+        let path = match &self.search_config.checkpoint {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        self.writer.flush()?;
+
+        let rc: Vec<Value> = self
+            .player_progress_rc
+            .iter()
+            .map(|(progress, rc)| json!([progress.to_json(), rc]))
+            .collect();
+        let data = json!({
+            "version": VERSION,
+            "rooms": self.level_info.room_count,
+            "configs": self.level_info.max_config_number,
+            "fingerprint": self.level_info.fingerprint,
+            "options": self.options_to_json(),
+            "output_length": self.writer.count,
+            "config": self.level_config,
+            "seconds": self.config_begin.elapsed().as_secs(),
+            "config_results": self
+                .config_results
+                .iter()
+                .map(|summary| self.summary_to_json(summary))
+                .collect::<Vec<_>>(),
+            "global_score": self.optimal_score_to_json(&self.global_optimal_player_by_score),
+            "global_stat": self.optimal_stat_to_json(&self.global_optimal_player_by_stat),
+            "local_score": self.optimal_score_to_json(&self.local_optimal_player_by_score),
+            "local_stat": self.optimal_stat_to_json(&self.local_optimal_player_by_stat),
+            "total_search_count": self.search_progress.total_search_count,
+            "current_search_count": self.search_progress.current_search_count,
+            "players": self
+                .optimal_player
+                .values()
+                .map(|player| player.to_json(self.level_info.keys()))
+                .collect::<Vec<_>>(),
+            "rc": rc,
+            "clones": self.clones.iter().map(PlayerProgress::to_json).collect::<Vec<_>>(),
+        });
+
+        let mut temp_path = OsString::from(&path);
+        temp_path.push(".tmp");
+        let mut file = BufWriter::new(File::create(&temp_path)?);
+        serde_json::to_writer(&mut file, &data)?;
+        file.flush()?;
+        drop(file);
+        fs::rename(&temp_path, &path)?;
+        writeln!(self.log_writer, "Saved checkpoint.")?;
+        Ok(())
+    }
+
+    // Restore results of finished configs and return the config to continue
+    pub(super) fn load_global_state(&mut self, checkpoint: &Checkpoint) -> io::Result<i32> {
+        let data = &checkpoint.data;
+        if int_field::<usize>(data, "rooms")? != self.level_info.room_count
+            || int_field::<i32>(data, "configs")? != self.level_info.max_config_number
+            || int_field::<u64>(data, "fingerprint")? != self.level_info.fingerprint
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "checkpoint was saved for a different level",
+            ));
+        }
+        if *field(data, "options")? != self.options_to_json() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "checkpoint was saved with different search options",
+            ));
+        }
+        let config = int_field(data, "config")?;
+        if config >= self.level_info.max_config_number {
+            return Err(invalid("config is out of range"));
+        }
+
+        self.writer.count = checkpoint.output_length()?;
+        for summary in array_field(data, "config_results")? {
+            let summary = self.summary_from_json(summary)?;
+            self.config_results.push(summary);
+        }
+        self.global_optimal_player_by_score =
+            self.optimal_score_from_json(field(data, "global_score")?)?;
+        self.global_optimal_player_by_stat = self.optimal_stat_from_json(data, "global_stat")?;
+        Ok(config)
+    }
+
+    // Restore the search of the current config after its level is loaded
+    pub(super) fn load_config_state(&mut self, checkpoint: &Checkpoint) -> io::Result<()> {
+        let data = &checkpoint.data;
+        self.local_optimal_player_by_score =
+            self.optimal_score_from_json(field(data, "local_score")?)?;
+        self.local_optimal_player_by_stat = self.optimal_stat_from_json(data, "local_stat")?;
+        self.search_progress.total_search_count = int_field(data, "total_search_count")?;
+        self.search_progress.current_search_count = int_field(data, "current_search_count")?;
+
+        for player in array_field(data, "players")? {
+            let player = Player::from_json(player, self.level_info.keys())?;
+            self.optimal_player.insert(player.progress.clone(), player);
+        }
+        for rc in array_field(data, "rc")? {
+            match rc.as_array().map(|rc| &rc[..]) {
+                Some([progress, count]) => {
+                    let count = count
+                        .as_i64()
+                        .and_then(|count| i32::try_from(count).ok())
+                        .ok_or_else(|| invalid("reference count is not a valid integer"))?;
+                    self.player_progress_rc
+                        .insert(PlayerProgress::from_json(progress)?, count);
+                }
+                _ => return Err(invalid("reference count is not a pair")),
+            }
+        }
+        for progress in array_field(data, "clones")? {
+            self.clones.push_back(PlayerProgress::from_json(progress)?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+use super::{LevelTrace, Player};
+use crate::drod::assets::Catalog;
+use crate::drod::bitset::word_count;
+use crate::drod::level_info::{LevelErrors, LevelInfo, Parser};
+use crate::drod::model::{PlayerFlag, RoomType, ScoreFunction};
+use crate::drod::{ChoiceIDType, Ge, VertexIDType};
+
+use serde_json::Value;
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+
+// Room of a proposed route, naming the branches picked in it when it has choices
+// and the items equipped for its fights when they are not the ones losing the least HP
+#[derive(Clone)]
+pub struct RouteStep {
+    pub room: String,
+    pub choice: Option<String>,
+    pub equip: Option<Vec<String>>,
+}
+
+impl RouteStep {
+    // Route given as an array of room names, or of objects with a room, a choice and the
+    // items equipped like the steps of the JSON output, whose other fields are ignored
+    pub fn parse_route(data: &Value) -> Result<Vec<Self>, LevelErrors> {
+        let mut parser = Parser::new(Catalog::default());
+        let mut route = Vec::new();
+        for (i, value) in parser.array(data, "$").iter().enumerate() {
+            let path = format!("$[{}]", i);
+            if value.is_string() {
+                if let Some(room) = parser.str(value, &path) {
+                    route.push(Self {
+                        room: room.to_owned(),
+                        choice: None,
+                        equip: None,
+                    });
+                }
+                continue;
+            }
+            let object = match parser.object(value, &path) {
+                Some(object) => object,
+                None => continue,
+            };
+            let room = match parser.get(object, "room", &path) {
+                Some(room) => parser.str(room, &format!("{}.room", path)),
+                None => None,
+            };
+            let choice = match object.get("choice") {
+                Some(choice) => parser.str(choice, &format!("{}.choice", path)),
+                None => None,
+            };
+            let equip = object.get("equip").map(|equip| {
+                let path = format!("{}.equip", path);
+                let names = parser.array(equip, &path);
+                names
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(j, name)| parser.str(name, &format!("{}[{}]", path, j)))
+                    .map(str::to_owned)
+                    .collect()
+            });
+            if let Some(room) = room {
+                route.push(Self {
+                    room: room.to_owned(),
+                    choice: choice.map(str::to_owned),
+                    equip,
+                });
+            }
+        }
+        parser.finish(route)
+    }
+}
+
+// Reason a room of a route cannot be visited next
+enum Illegal {
+    UnknownRoom,
+    NotNeighbor,
+    UnknownChoice(String),
+    MissingChoice,
+    UnknownEquip,
+    PlayerDies,
+    RequirementNotMet,
+}
+
+impl Display for Illegal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownRoom => write!(f, "the room is not in the level"),
+            Self::NotNeighbor => write!(f, "the room cannot be reached from the rooms visited"),
+            Self::UnknownChoice(name) => write!(f, "the room has no choice \"{}\"", name),
+            Self::MissingChoice => write!(f, "the room has choices but none is picked"),
+            Self::UnknownEquip => write!(f, "its fights cannot be fought with the items given"),
+            Self::PlayerDies => write!(f, "the player dies"),
+            Self::RequirementNotMet => write!(f, "a requirement of the room is not met"),
+        }
+    }
+}
+
+impl<const N: usize> LevelTrace<N> {
+    // Room of the step and the index of its result with the choices and items given,
+    // when it can be visited next
+    fn check_step(&self, step: &RouteStep) -> Result<(VertexIDType, ChoiceIDType), Illegal> {
+        let level = &self.level;
+        let player = &self.player;
+        let id = level.find_name(&step.room).ok_or(Illegal::UnknownRoom)?;
+
+        // Rooms after an intermediate room are neighbors of it, as in the search
+        let last = player.diff.location;
+        let was_intermediate = last != VertexIDType::MAX
+            && level
+                .vertex_of_id(last)
+                .room_type
+                .contains(RoomType::INTERMEDIATE);
+        if !player.neighbors.get_bit(id as usize)
+            || was_intermediate && !level.neighbors[last as usize].get_bit(id as usize)
+        {
+            return Err(Illegal::NotNeighbor);
+        }
+
+        let room = level.vertex_of_id(id);
+        let choice = match &step.choice {
+            Some(name) if room.choice_count() > 1 => room
+                .choice_names()
+                .iter()
+                .position(|other| other == name)
+                .ok_or_else(|| Illegal::UnknownChoice(name.clone()))?,
+            Some(name) => return Err(Illegal::UnknownChoice(name.clone())),
+            None if room.choice_count() == 1 => 0,
+            None => return Err(Illegal::MissingChoice),
+        };
+
+        // Items equipped for the fights are those losing the least HP unless given
+        let probes = level.probe(id, player.stat.as_ref());
+        let index = probes
+            .iter()
+            .position(|probe| {
+                probe.choice == choice
+                    && step
+                        .equip
+                        .as_ref()
+                        .is_none_or(|equip| *equip == level.equip_names(&probe.equips))
+            })
+            .ok_or(Illegal::UnknownEquip)?;
+        let probe = &probes[index];
+        if probe.req.as_ref().flag.contains(PlayerFlag::DEAD) || player.stat.hp < probe.req.hp {
+            Err(Illegal::PlayerDies)
+        } else if !player.ge(&probe.req) || probe.counters(&player.progress.counters).is_none() {
+            Err(Illegal::RequirementNotMet)
+        } else {
+            Ok((id, index as ChoiceIDType))
+        }
+    }
+}
+
+// Follow the route through the config of the level, writing the stats after every room
+// and the score. The route stops at the first room that cannot be visited.
+// Returns whether every room can be visited and the route ends at the exit.
+pub fn verify(
+    level_info: &LevelInfo,
+    config: i32,
+    score: Option<ScoreFunction>,
+    route: &[RouteStep],
+    writer: &mut dyn Write,
+) -> io::Result<bool> {
+    if config < 0 || config >= level_info.config_count() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("config {} is not in the level", config),
+        ));
+    }
+    match word_count(level_info.room_count) {
+        1 => verify_with_width::<1>(level_info, config, score, route, writer),
+        2 => verify_with_width::<2>(level_info, config, score, route, writer),
+        _ => verify_with_width::<4>(level_info, config, score, route, writer),
+    }
+}
+
+fn verify_with_width<const N: usize>(
+    level_info: &LevelInfo,
+    config: i32,
+    score: Option<ScoreFunction>,
+    route: &[RouteStep],
+    writer: &mut dyn Write,
+) -> io::Result<bool> {
+    let mut level = level_info.build::<N>(config)?;
+    if let Some(score) = score {
+        level.score = score;
+    }
+    let mut player = Player {
+        stat: level_info.init_player(),
+        ..Default::default()
+    };
+    player.progress.counters = level_info.init_counters();
+    player.enter(&level);
+    let mut trace = LevelTrace {
+        level_config: config,
+        level: Rc::new(level),
+        player,
+        trace: Vec::new(),
+    };
+
+    level_info.print_config(writer, config)?;
+    let mut illegal = None;
+    for (i, step) in route.iter().enumerate() {
+        match trace.check_step(step) {
+            Ok((id, choice)) => {
+                let probe = trace.visit(id, choice);
+                write!(
+                    writer,
+                    "{}: {}",
+                    trace.step_name(id, &probe),
+                    trace.player.stat.display(&trace.level.keys)
+                )?;
+            }
+            Err(reason) => {
+                illegal = Some((i, reason));
+                break;
+            }
+        }
+    }
+    writeln!(writer, "Score: {}", trace.player.score(&trace.level))?;
+
+    let last = trace.trace.last().map(|&(id, _)| id);
+    match illegal {
+        Some((i, reason)) => {
+            writeln!(
+                writer,
+                "Room {} of the route, {}, cannot be visited: {}",
+                i + 1,
+                route[i].room,
+                reason
+            )?;
+            Ok(false)
+        }
+        None if last != Some(trace.level.exit) => {
+            writeln!(writer, "The route does not end at the exit")?;
+            Ok(false)
+        }
+        None => Ok(true),
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+// Hash map split by key into shards that can be updated by different threads
+pub(super) struct ShardedMap<K, V> {
+    shards: Vec<HashMap<K, V>>,
+}
+
+impl<K: Eq + Hash, V> ShardedMap<K, V> {
+    pub(super) fn new(shard_count: usize) -> Self {
+        Self {
+            shards: (0..shard_count).map(|_| HashMap::new()).collect(),
+        }
+    }
+
+    pub(super) fn shard_index(&self, key: &K) -> usize {
+        if self.shards.len() == 1 {
+            return 0;
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    pub(super) fn shard_mut(&mut self, key: &K) -> &mut HashMap<K, V> {
+        let index = self.shard_index(key);
+        &mut self.shards[index]
+    }
+
+    #[cfg(not(feature = "closed-level"))]
+    pub(super) fn shards_mut(&mut self) -> std::slice::IterMut<'_, HashMap<K, V>> {
+        self.shards.iter_mut()
+    }
+
+    pub(super) fn get(&self, key: &K) -> Option<&V> {
+        self.shards[self.shard_index(key)].get(key)
+    }
+
+    pub(super) fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.shard_mut(&key).insert(key, value)
+    }
+
+    pub(super) fn remove(&mut self, key: &K) -> Option<V> {
+        self.shard_mut(key).remove(key)
+    }
+
+    pub(super) fn values(&self) -> impl Iterator<Item = &V> {
+        self.shards.iter().flat_map(|shard| shard.values())
+    }
+
+    pub(super) fn clear(&mut self) {
+        for shard in &mut self.shards {
+            shard.clear();
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use super::level_info::LevelInfo;
+use super::model::PlayerStat;
+
+use serde_json::{json, Value};
+
+// Stats right after leaving a room of a trace.
+// The choice names the branches picked in a room that has choices,
+// and equip names the items switched to for its fights.
+#[derive(Clone)]
+pub struct TraceStep {
+    pub room: String,
+    pub choice: Option<String>,
+    pub equip: Vec<String>,
+    pub stat: PlayerStat,
+}
+
+// Route from the entrance to the exit of a config.
+// HP is not shifted in any of the stats, as in the player of a level file.
+#[derive(Clone)]
+pub struct PlayerTrace {
+    pub config: i32,
+    pub score: f64,
+    pub init_stat: PlayerStat,
+    pub stat: PlayerStat,
+    pub trace: Vec<TraceStep>,
+}
+
+impl PlayerTrace {
+    pub(super) fn to_json(&self, level_info: &LevelInfo) -> Value {
+        let steps: Vec<Value> = self
+            .trace
+            .iter()
+            .map(|step| {
+                let mut value = json!({
+                    "room": step.room,
+                    "stat": step.stat.to_json(level_info.keys()),
+                });
+                if let Some(choice) = &step.choice {
+                    value["choice"] = Value::from(choice.clone());
+                }
+                if !step.equip.is_empty() {
+                    value["equip"] = Value::from(step.equip.clone());
+                }
+                value
+            })
+            .collect();
+        json!({
+            "config": level_info.config_to_json(self.config),
+            "score": self.score,
+            "init_stat": self.init_stat.to_json(level_info.keys()),
+            "stat": self.stat.to_json(level_info.keys()),
+            "trace": steps,
+        })
+    }
+}
+
+fn optimal_to_json(optimal: &Option<PlayerTrace>, level_info: &LevelInfo) -> Value {
+    match optimal {
+        Some(trace) => trace.to_json(level_info),
+        None => Value::Null,
+    }
+}
+
+fn pareto_to_json(pareto: &Option<Vec<PlayerTrace>>, level_info: &LevelInfo) -> Value {
+    match pareto {
+        Some(traces) => traces
+            .iter()
+            .map(|trace| trace.to_json(level_info))
+            .collect(),
+        None => Value::Null,
+    }
+}
+
+// Outcome of searching a single config.
+// The optimal trace is missing when the exit cannot be reached,
+// and the pareto set is missing when it is not calculated.
+#[derive(Clone)]
+pub struct ConfigResult {
+    pub config: i32,
+    pub situations: usize,
+    pub seconds: u64,
+    pub optimal: Option<PlayerTrace>,
+    pub pareto: Option<Vec<PlayerTrace>>,
+}
+
+impl ConfigResult {
+    // The time taken is left out so that the same search always writes the same document
+    fn to_json(&self, level_info: &LevelInfo) -> Value {
+        json!({
+            "config": level_info.config_to_json(self.config),
+            "situations": self.situations,
+            "optimal": optimal_to_json(&self.optimal, level_info),
+            "pareto": pareto_to_json(&self.pareto, level_info),
+        })
+    }
+}
+
+// Outcome of searching every config, with the best traces across all of them
+#[derive(Clone)]
+pub struct SearchResult {
+    pub configs: Vec<ConfigResult>,
+    pub optimal: Option<PlayerTrace>,
+    pub pareto: Option<Vec<PlayerTrace>>,
+}
+
+impl SearchResult {
+    // Document written with the JSON output format
+    pub(super) fn to_json(&self, level_info: &LevelInfo) -> Value {
+        let configs: Vec<Value> = self
+            .configs
+            .iter()
+            .map(|config| config.to_json(level_info))
+            .collect();
+        json!({
+            "version": 1,
+            "configs": configs,
+            "optimal": optimal_to_json(&self.optimal, level_info),
+            "pareto": pareto_to_json(&self.pareto, level_info),
+        })
+    }
+}
+
+// Events reported while searching. Every method does nothing unless overridden.
+pub trait SearchCallback {
+    // Situations expanded and found so far in the current config, reported every so often
+    fn progress(&mut self, _searched: usize, _found: usize) {}
+
+    // Trace with a better score than any found before in the current config
+    fn new_highscore(&mut self, _trace: &PlayerTrace) {}
+
+    fn config_finished(&mut self, _result: &ConfigResult) {}
+}
+
+impl SearchCallback for () {}
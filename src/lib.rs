@@ -0,0 +1,14 @@
+extern crate bitflags;
+extern crate lazy_static;
+extern crate serde_json;
+extern crate structopt;
+
+mod drod;
+
+pub use drod::{
+    search, solve, verify, write_breakpoints, Branch, Catalog, Checkpoint, ConfigResult, Element,
+    EquipStat, FightInfo, FightStat, HpBoostStat, LevelBuilder, LevelError, LevelErrorKind,
+    LevelErrors, LevelInfo, MonsterFlag, MonsterStat, OutputFormat, PlayerFlag, PlayerStat,
+    PlayerTrace, Room, RoomType, RouteStep, ScoreFunction, SearchCallback, SearchConfig,
+    SearchResult, TraceStep, MAX_KEY_COUNT,
+};
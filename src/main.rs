@@ -1,48 +1,281 @@
-extern crate bitflags;
-extern crate lazy_static;
-extern crate rust_dense_bitset;
+extern crate drod_rpg_solver;
 extern crate serde_json;
 extern crate structopt;
 
-mod drod;
-
-use drod::{LevelInfo, Search, SearchConfig};
+use drod_rpg_solver::{
+    verify, write_breakpoints, Catalog, Checkpoint, FightInfo, LevelInfo, RouteStep, ScoreFunction,
+    SearchConfig,
+};
 
+use serde_json::Value;
+use structopt::clap;
 use structopt::StructOpt;
 
 use std::fs;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io;
+use std::io::{Seek, SeekFrom};
 use std::path::PathBuf;
+use std::process;
 
 #[derive(StructOpt)]
 #[structopt(no_version, about)]
 struct Config {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
     #[structopt(flatten)]
     search_config: SearchConfig,
 
+    /// Catalog file with named monsters and equipment added to the built-in ones
+    #[structopt(long, parse(from_os_str))]
+    catalog: Option<PathBuf>,
+
+    /// Checkpoint file to continue an interrupted search from
+    #[structopt(long, parse(from_os_str))]
+    resume: Option<PathBuf>,
+
+    /// Input file, required without a subcommand
+    #[structopt(parse(from_os_str))]
+    input: Option<PathBuf>,
+
+    /// Output file, required without a subcommand
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Print the cost of fighting a monster and the ATK and DEF at which it changes
+    #[structopt(no_version)]
+    Fight(FightConfig),
+
+    /// Write a table of the ATK and DEF at which each monster of a level costs less HP
+    #[structopt(no_version)]
+    Breakpoints(BreakpointsConfig),
+
+    /// Follow a route through a level, printing the stats after every room and the score
+    #[structopt(no_version)]
+    Verify(VerifyConfig),
+}
+
+#[derive(StructOpt)]
+struct FightConfig {
+    /// Player ATK, including the equipment
+    #[structopt(long)]
+    atk: i16,
+
+    /// Player DEF, including the equipment
+    #[structopt(long)]
+    def: i16,
+
+    /// Player flag such as HAS_WEAPON, given once for each flag
+    #[structopt(long = "flag", number_of_values = 1)]
+    flags: Vec<String>,
+
+    /// Equipped weapon, shield or accessory by catalog name or as JSON stats, given once for each
+    #[structopt(long = "equip", number_of_values = 1)]
+    equips: Vec<String>,
+
+    /// Number of ATK and DEF breakpoints to print
+    #[structopt(long, default_value = "5")]
+    breakpoints: usize,
+
+    /// Catalog file with named monsters and equipment added to the built-in ones
+    #[structopt(long, parse(from_os_str))]
+    catalog: Option<PathBuf>,
+
+    /// Monster by catalog name or as JSON stats, as in level files
+    monster: String,
+}
+
+#[derive(StructOpt)]
+struct BreakpointsConfig {
+    /// Player ATK replacing the initial one of the level
+    #[structopt(long)]
+    atk: Option<i16>,
+
+    /// Player DEF replacing the initial one of the level
+    #[structopt(long)]
+    def: Option<i16>,
+
+    /// Number of ATK and DEF breakpoints to list for each monster
+    #[structopt(long, default_value = "5")]
+    breakpoints: usize,
+
+    /// Catalog file with named monsters and equipment added to the built-in ones
+    #[structopt(long, parse(from_os_str))]
+    catalog: Option<PathBuf>,
+
     /// Input file
     #[structopt(parse(from_os_str))]
     input: PathBuf,
 
-    /// Output file
+    /// Output file for the table in CSV
     #[structopt(parse(from_os_str))]
     output: PathBuf,
 }
 
-fn main() -> io::Result<()> {
-    let config = Config::from_args();
-    let input_data = fs::read(config.input)?;
+#[derive(StructOpt)]
+struct VerifyConfig {
+    /// Level config to follow the route in
+    #[structopt(long, default_value = "0")]
+    config: i32,
+
+    /// Score function replacing the one in the level file (drod or tots)
+    #[structopt(long)]
+    score: Option<ScoreFunction>,
+
+    /// Catalog file with named monsters and equipment added to the built-in ones
+    #[structopt(long, parse(from_os_str))]
+    catalog: Option<PathBuf>,
+
+    /// Input file
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+
+    /// Route file with a JSON array of rooms
+    #[structopt(parse(from_os_str))]
+    route: PathBuf,
+}
+
+fn load_catalog(path: Option<PathBuf>) -> io::Result<Catalog> {
+    let mut catalog = Catalog::builtin();
+    if let Some(path) = path {
+        let catalog_data = fs::read(path)?;
+        let json_value = serde_json::from_slice(&catalog_data)?;
+        if let Err(errors) = catalog.load(&json_value) {
+            eprintln!("{}", errors);
+            process::exit(1);
+        }
+    }
+    Ok(catalog)
+}
+
+// Catalog name, or JSON stats when the argument is an object
+fn name_or_stats(arg: &str) -> io::Result<Value> {
+    if arg.trim_start().starts_with('{') {
+        Ok(serde_json::from_str(arg)?)
+    } else {
+        Ok(Value::from(arg))
+    }
+}
+
+fn fight(config: FightConfig) -> io::Result<()> {
+    let catalog = load_catalog(config.catalog)?;
+    let equips = config
+        .equips
+        .iter()
+        .map(|equip| name_or_stats(equip))
+        .collect::<io::Result<Vec<_>>>()?;
+    let json_value = serde_json::json!({
+        "player": {"atk": config.atk, "def": config.def, "flag": config.flags},
+        "equip": equips,
+        "monster": name_or_stats(&config.monster)?,
+    });
+    let fight_info = match FightInfo::new(json_value, &catalog) {
+        Ok(fight_info) => fight_info,
+        Err(errors) => {
+            eprintln!("{}", errors);
+            process::exit(1);
+        }
+    };
+    fight_info.print(&mut io::stdout(), config.breakpoints)
+}
+
+fn load_level(path: PathBuf, catalog: &Catalog) -> io::Result<LevelInfo> {
+    let input_data = fs::read(path)?;
     let json_value = serde_json::from_slice(&input_data)?;
-    let level_info = LevelInfo::new(json_value)?;
+    match LevelInfo::new(json_value, catalog) {
+        Ok(level_info) => Ok(level_info),
+        Err(errors) => {
+            eprintln!("{}", errors);
+            process::exit(1);
+        }
+    }
+}
 
+fn breakpoints(config: BreakpointsConfig) -> io::Result<()> {
+    let catalog = load_catalog(config.catalog)?;
+    let level_info = load_level(config.input, &catalog)?;
     let mut output_file = File::create(config.output)?;
+    write_breakpoints(
+        &level_info,
+        config.atk,
+        config.def,
+        config.breakpoints,
+        &mut output_file,
+    )
+}
+
+// Exit with an error status when the route cannot be followed to the exit
+fn verify_route(config: VerifyConfig) -> io::Result<()> {
+    let catalog = load_catalog(config.catalog)?;
+    let level_info = load_level(config.input, &catalog)?;
+    let route_data = fs::read(config.route)?;
+    let json_value = serde_json::from_slice(&route_data)?;
+    let route = match RouteStep::parse_route(&json_value) {
+        Ok(route) => route,
+        Err(errors) => {
+            eprintln!("found {} problem(s) in route:", errors.0.len());
+            for error in &errors.0 {
+                eprintln!("  {}", error);
+            }
+            process::exit(1);
+        }
+    };
+    let mut stdout = io::stdout();
+    if !verify(
+        &level_info,
+        config.config,
+        config.score,
+        &route,
+        &mut stdout,
+    )? {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    let config = Config::from_args();
+    let (input, output) = match (config.command, config.input, config.output) {
+        (Some(Command::Fight(fight_config)), _, _) => return fight(fight_config),
+        (Some(Command::Breakpoints(breakpoints_config)), _, _) => {
+            return breakpoints(breakpoints_config)
+        }
+        (Some(Command::Verify(verify_config)), _, _) => return verify_route(verify_config),
+        (None, Some(input), Some(output)) => (input, output),
+        _ => clap::Error::with_description(
+            "The input and output files are required",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
+    let catalog = load_catalog(config.catalog)?;
+
+    let level_info = load_level(input, &catalog)?;
+
+    // Output after the checkpoint is written again when resuming
+    let checkpoint = match config.resume {
+        Some(path) => Some(Checkpoint::load(&path)?),
+        None => None,
+    };
+    let mut output_file = match &checkpoint {
+        Some(checkpoint) => {
+            let mut file = OpenOptions::new().write(true).open(&output)?;
+            file.set_len(checkpoint.output_length()?)?;
+            file.seek(SeekFrom::End(0))?;
+            file
+        }
+        None => File::create(&output)?,
+    };
     let mut stdout = io::stdout();
-    let mut search = Search::new(
+    drod_rpg_solver::search(
         config.search_config,
         level_info,
+        checkpoint,
         &mut output_file,
         &mut stdout,
-    );
-    search.search()
+    )
 }
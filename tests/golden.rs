@@ -0,0 +1,116 @@
+// The reference levels are open levels; closed levels read their arcs differently
+#![cfg(not(feature = "closed-level"))]
+
+use drod_rpg_solver::{solve, verify, Catalog, LevelInfo, PlayerStat, RouteStep, SearchConfig};
+
+use serde_json::Value;
+
+use std::fs;
+use std::path::Path;
+
+fn read_json(path: &Path) -> Value {
+    let data = fs::read(path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+    serde_json::from_slice(&data).unwrap_or_else(|err| panic!("{}: {}", path.display(), err))
+}
+
+// Stat of the player by the name used in level files
+fn stat_field(stat: &PlayerStat, keys: &[String], name: &str) -> Option<i64> {
+    match name {
+        "hp" => Some(stat.hp() as i64),
+        "atk" => Some(stat.atk() as i64),
+        "def" => Some(stat.def() as i64),
+        "gr" => Some(stat.gr() as i64),
+        "rep" => Some(stat.rep() as i64),
+        _ => keys
+            .iter()
+            .position(|key| key == name)
+            .map(|index| stat.keys()[index] as i64),
+    }
+}
+
+// Solve the level of a golden file and compare the optimal trace with the recorded one
+fn check_golden(root: &Path, golden_path: &Path) {
+    let golden = read_json(golden_path);
+    let name = golden_path.display();
+    let mut catalog = Catalog::builtin();
+    if let Some(path) = golden["catalog"].as_str() {
+        catalog
+            .load(&read_json(&root.join(path)))
+            .unwrap_or_else(|err| panic!("{}: {}", name, err));
+    }
+    let level_path = root.join(
+        golden["level"]
+            .as_str()
+            .expect("golden file names no level"),
+    );
+    let load_level = || {
+        LevelInfo::new(read_json(&level_path), &catalog)
+            .unwrap_or_else(|err| panic!("{}: {}", name, err))
+    };
+    let level_info = load_level();
+    let keys = level_info.keys().to_vec();
+
+    let result = solve(SearchConfig::default(), level_info, &mut ())
+        .unwrap_or_else(|err| panic!("{}: {}", name, err));
+    let optimal = result
+        .optimal
+        .unwrap_or_else(|| panic!("{}: the exit cannot be reached", name));
+    assert_eq!(
+        optimal.score,
+        golden["score"].as_f64().unwrap(),
+        "{}: score",
+        name
+    );
+    assert_eq!(
+        optimal.trace.len() as u64,
+        golden["trace_length"].as_u64().unwrap(),
+        "{}: trace length",
+        name
+    );
+    for (field, value) in golden["stat"].as_object().unwrap() {
+        let actual = stat_field(&optimal.stat, &keys, field)
+            .unwrap_or_else(|| panic!("{}: unknown stat \"{}\"", name, field));
+        assert_eq!(Some(actual), value.as_i64(), "{}: {}", name, field);
+    }
+
+    // Following the optimal trace as a route gives the same score
+    let route: Vec<RouteStep> = optimal
+        .trace
+        .iter()
+        .map(|step| RouteStep {
+            room: step.room.clone(),
+            choice: step.choice.clone(),
+            equip: Some(step.equip.clone()),
+        })
+        .collect();
+    let mut output = Vec::new();
+    let legal = verify(&load_level(), optimal.config, None, &route, &mut output)
+        .unwrap_or_else(|err| panic!("{}: {}", name, err));
+    let output = String::from_utf8(output).unwrap();
+    assert!(
+        legal,
+        "{}: the optimal trace is not a legal route\n{}",
+        name, output
+    );
+    assert!(
+        output.contains(&format!("Score: {:.3}\n", optimal.score)),
+        "{}: score of the route\n{}",
+        name,
+        output
+    );
+}
+
+#[test]
+fn golden_levels() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths: Vec<_> = fs::read_dir(root.join("tests/golden"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no golden files found");
+    for path in paths {
+        check_golden(root, &path);
+    }
+}
@@ -0,0 +1,569 @@
+// Small levels are open levels; closed levels read their arcs differently
+#![cfg(not(feature = "closed-level"))]
+
+use drod_rpg_solver::{
+    search, solve, Catalog, Checkpoint, LevelInfo, OutputFormat, PlayerTrace, ScoreFunction,
+    SearchConfig, SearchResult,
+};
+
+use serde_json::{json, Value};
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+fn solve_level(data: Value) -> SearchResult {
+    let level_info = LevelInfo::new(data, &Catalog::builtin()).unwrap();
+    solve(SearchConfig::default(), level_info, &mut ()).unwrap()
+}
+
+fn optimal(data: Value) -> PlayerTrace {
+    solve_level(data)
+        .optimal
+        .expect("the exit cannot be reached")
+}
+
+fn room_names(trace: &PlayerTrace) -> Vec<&str> {
+    trace.trace.iter().map(|step| step.room.as_str()).collect()
+}
+
+// Room, picked choice and main stats after every step
+fn steps(trace: &PlayerTrace) -> Vec<(String, Option<String>, i32, i16, i16, i16)> {
+    trace
+        .trace
+        .iter()
+        .map(|step| {
+            let stat = &step.stat;
+            let (hp, atk, def, gr) = (stat.hp(), stat.atk(), stat.def(), stat.gr());
+            (step.room.clone(), step.choice.clone(), hp, atk, def, gr)
+        })
+        .collect()
+}
+
+fn read_level(name: &str) -> Value {
+    let path = format!("{}/levels/{}", env!("CARGO_MANIFEST_DIR"), name);
+    serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+}
+
+// Shops with choices, and configs whose best one is not the first
+fn choice_level() -> Value {
+    json!({
+        "player": {"hp": 500, "atk": 16, "def": 10},
+        "rooms": [
+            {"name": "S", "content": [{"resource": {"gr": 50}}]},
+            {"name": "Shop", "content": [{"cost": {"gr": 30}}, {"choice": [
+                {"name": "sword", "content": [{"equipment": {"atk": 8}}]},
+                {"name": "shield", "content": [{"equipment": {"def": 8}}]}
+            ]}]},
+            {"name": "Market", "content": [
+                {"shop": {"name": "DEF", "goods": {"def": 2}, "prices": [10, 20, 30]}}
+            ]},
+            {"name": "M1", "content": [{"monster": {"hp": 40, "atk": 20, "def": 5, "gr": 10}}]},
+            {"name": "M2", "content": [{"monster": {"hp": 60, "atk": 25, "def": 8, "gr": 15}}]},
+            {"name": "M3", "content": [{"monster": {"hp": 30, "atk": 30, "def": 12, "gr": 10}}]},
+            {"name": "M4", "content": [{"monster": {"hp": 80, "atk": 22, "def": 10, "gr": 20}}]},
+            {"name": "M5", "content": [{"monster": {"hp": 60, "atk": 30, "def": 12, "gr": 5}}]},
+            {"name": "P1", "content": [{"resource": {"hp": 50}}]},
+            {"name": "P2", "content": [{"resource": {"hp": 80}}]},
+            {"name": "P3", "content": [{"resource": {"atk": 3}}]},
+            {"name": "P4", "content": [{"resource": {"def": 3}}]},
+            {"name": "E"}
+        ],
+        "arcs": [
+            ["S", "Shop"], ["S", "M1"], ["S", "M2"], ["M1", "P1"], ["M2", "P2"],
+            ["M1", "M3"], ["M2", "M4"], ["M3", "P3"], ["M4", "P4"], ["M3", "M5"],
+            ["M4", "M5"], ["M5", "E"], ["P1", "Market"]
+        ],
+        "configs": [
+            {"name": "Treasure", "options": [
+                {"name": "hp", "rooms": [{"name": "T", "content": [{"resource": {"hp": 100}}]}],
+                 "arcs": [["M3", "T"]]},
+                {"name": "atk", "rooms": [{"name": "T", "content": [{"resource": {"atk": 5}}]}],
+                 "arcs": [["M4", "T"]]}
+            ]},
+            {"name": "Guard", "options": [
+                {"name": "weak"},
+                {"name": "strong",
+                 "rooms": [{"name": "G", "content": [{"monster": {"hp": 50, "atk": 40, "def": 14}}]}],
+                 "arcs": [["S", "G"], ["G", "Market"]]}
+            ]}
+        ],
+        "entrance": "S",
+        "exit": "E"
+    })
+}
+
+// Rooms stay out of the neighbors once visited, even when an edge leads back to them
+#[test]
+fn visited_rooms_are_not_visited_again() {
+    let trace = optimal(json!({
+        "player": {"hp": 100, "atk": 10, "def": 10},
+        "rooms": [
+            {"name": "S"},
+            {"name": "A", "content": [{"resource": {"hp": 100}}]},
+            {"name": "B", "content": [{"resource": {"hp": 10}}]},
+            {"name": "E"}
+        ],
+        "arcs": [["S", "A"], ["A", "E"]],
+        "edges": [["A", "B"]],
+        "entrance": "S",
+        "exit": "E"
+    }));
+    assert_eq!(room_names(&trace), ["S", "A", "B", "E"]);
+    assert_eq!(trace.stat.hp(), 210);
+}
+
+// Estimating the best stats probes rooms with stats far above those of any player,
+// which must leave room for the boosts in the rooms
+#[test]
+fn estimated_stats_do_not_overflow() {
+    let trace = optimal(json!({
+        "player": {"hp": 100, "atk": 10, "def": 10},
+        "rooms": [
+            {"name": "S"},
+            {"name": "A", "content": [{"resource": {"atk": 1000, "def": 1000}}]},
+            {"name": "E"}
+        ],
+        "arcs": [["S", "A"], ["A", "E"]],
+        "entrance": "S",
+        "exit": "E"
+    }));
+    assert_eq!(trace.stat.atk(), 1010);
+    assert_eq!(trace.stat.def(), 1010);
+}
+
+// The JSON output of a search is the same every time
+#[test]
+fn json_output_is_reproducible() {
+    let data = json!({
+        "player": {"hp": 100, "atk": 10, "def": 10},
+        "rooms": [
+            {"name": "S"},
+            {"name": "A", "content": [{"monster": {"hp": 20, "atk": 15, "def": 5}}]},
+            {"name": "E"}
+        ],
+        "arcs": [["S", "A"], ["A", "E"]],
+        "entrance": "S",
+        "exit": "E"
+    });
+    let write_json = || {
+        let level_info = LevelInfo::new(data.clone(), &Catalog::builtin()).unwrap();
+        let search_config = SearchConfig {
+            format: OutputFormat::Json,
+            ..SearchConfig::default()
+        };
+        let mut output = Vec::new();
+        search(
+            search_config,
+            level_info,
+            None,
+            &mut output,
+            &mut io::sink(),
+        )
+        .unwrap();
+        serde_json::from_slice::<Value>(&output).unwrap()
+    };
+    let output = write_json();
+    assert_eq!(output, write_json());
+    let config = output["configs"][0].as_object().unwrap();
+    assert!(!config.contains_key("seconds"));
+    assert_eq!(output["optimal"]["stat"]["hp"], 80);
+}
+
+// Searching with several threads finds the same optimum with the same trace in every config
+#[test]
+fn parallel_search_matches_sequential() {
+    for data in [read_level("DarkOozerDefeated.json"), choice_level()] {
+        let solve_with_threads = |threads| {
+            let level_info = LevelInfo::new(data.clone(), &Catalog::builtin()).unwrap();
+            let search_config = SearchConfig {
+                threads,
+                ..SearchConfig::default()
+            };
+            solve(search_config, level_info, &mut ()).unwrap()
+        };
+        let sequential = solve_with_threads(1);
+        let parallel = solve_with_threads(4);
+        assert_eq!(sequential.configs.len(), parallel.configs.len());
+        for (sequential, parallel) in sequential.configs.iter().zip(&parallel.configs) {
+            assert_eq!(sequential.situations, parallel.situations);
+            let sequential = sequential.optimal.as_ref().unwrap();
+            let parallel = parallel.optimal.as_ref().unwrap();
+            assert_eq!(sequential.score, parallel.score);
+            assert_eq!(steps(sequential), steps(parallel));
+        }
+        let sequential = sequential.optimal.unwrap();
+        let parallel = parallel.optimal.unwrap();
+        assert_eq!(sequential.config, parallel.config);
+        assert_eq!(steps(&sequential), steps(&parallel));
+    }
+}
+
+// Log that fails the search once the given number of checkpoints have been saved
+struct InterruptAfter(usize);
+
+impl Write for InterruptAfter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let message = b"Saved checkpoint.";
+        let saved = buf.windows(message.len()).filter(|&w| w == message).count();
+        self.0 = self.0.saturating_sub(saved);
+        if self.0 == 0 {
+            Err(io::Error::other("interrupted"))
+        } else {
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn checkpoint_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("drod-{}-{}.json", name, std::process::id()))
+}
+
+// Few situations in two configs, with a shop whose best choice is not the first
+fn shop_level() -> Value {
+    json!({
+        "player": {"hp": 300, "atk": 12, "def": 10},
+        "rooms": [
+            {"name": "S", "content": [{"resource": {"gr": 30}}]},
+            {"name": "Shop", "content": [{"cost": {"gr": 20}}, {"choice": [
+                {"name": "shield", "content": [{"equipment": {"def": 5}}]},
+                {"name": "sword", "content": [{"equipment": {"atk": 5}}]}
+            ]}]},
+            {"name": "M1", "content": [{"monster": {"hp": 40, "atk": 20, "def": 5, "gr": 10}}]},
+            {"name": "M2", "content": [{"monster": {"hp": 50, "atk": 25, "def": 8, "gr": 10}}]},
+            {"name": "P1", "content": [{"resource": {"hp": 50}}]},
+            {"name": "E"}
+        ],
+        "arcs": [["S", "Shop"], ["S", "M1"], ["M1", "P1"], ["M1", "M2"], ["M2", "E"]],
+        "configs": [
+            {"name": "Treasure", "options": [
+                {"name": "hp", "rooms": [{"name": "T", "content": [{"resource": {"hp": 100}}]}],
+                 "arcs": [["M1", "T"]]},
+                {"name": "atk", "rooms": [{"name": "T", "content": [{"resource": {"atk": 5}}]}],
+                 "arcs": [["M1", "T"]]}
+            ]}
+        ],
+        "entrance": "S",
+        "exit": "E"
+    })
+}
+
+// Search with a checkpoint saved after every situation, writing JSON
+fn search_json(
+    data: &Value,
+    path: &Path,
+    score: Option<ScoreFunction>,
+    checkpoint: Option<Checkpoint>,
+    output: &mut Vec<u8>,
+    log_writer: &mut dyn Write,
+) -> io::Result<()> {
+    let level_info = LevelInfo::new(data.clone(), &Catalog::builtin()).unwrap();
+    let search_config = SearchConfig {
+        format: OutputFormat::Json,
+        score,
+        checkpoint: Some(path.to_owned()),
+        checkpoint_interval: 0,
+        ..SearchConfig::default()
+    };
+    search(search_config, level_info, checkpoint, output, log_writer)
+}
+
+// Resuming an interrupted search writes the same output as searching without interruption
+#[test]
+fn resumed_search_matches_uninterrupted() {
+    let data = shop_level();
+    let path = checkpoint_path("resume");
+    let mut expected = Vec::new();
+    search_json(&data, &path, None, None, &mut expected, &mut io::sink()).unwrap();
+
+    // A checkpoint is saved after every situation, so the search is interrupted
+    // after each of them in turn until it finishes before the next checkpoint
+    for stop in 1.. {
+        let mut output = Vec::new();
+        let interrupted = search_json(
+            &data,
+            &path,
+            None,
+            None,
+            &mut output,
+            &mut InterruptAfter(stop),
+        );
+        if interrupted.is_ok() {
+            assert!(stop > 10, "only {} checkpoints saved", stop - 1);
+            break;
+        }
+
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        output.truncate(checkpoint.output_length().unwrap() as usize);
+        search_json(
+            &data,
+            &path,
+            None,
+            Some(checkpoint),
+            &mut output,
+            &mut io::sink(),
+        )
+        .unwrap();
+        assert!(
+            output == expected,
+            "different output after checkpoint {}",
+            stop
+        );
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+// A checkpoint is only resumed for the same level and score function
+#[test]
+fn checkpoint_of_other_search_is_rejected() {
+    let data = shop_level();
+    let path = checkpoint_path("reject");
+    let mut output = Vec::new();
+    let interrupted = search_json(
+        &data,
+        &path,
+        None,
+        None,
+        &mut output,
+        &mut InterruptAfter(1),
+    );
+    assert!(interrupted.is_err());
+
+    // Same number of rooms and configs, but a stronger monster
+    let mut other = data.clone();
+    other["rooms"][2]["content"][0]["monster"]["atk"] = json!(21);
+    let resumed = search_json(
+        &other,
+        &path,
+        None,
+        Some(Checkpoint::load(&path).unwrap()),
+        &mut Vec::new(),
+        &mut io::sink(),
+    );
+    assert_eq!(
+        resumed.unwrap_err().to_string(),
+        "checkpoint was saved for a different level"
+    );
+
+    let resumed = search_json(
+        &data,
+        &path,
+        Some(ScoreFunction::tots()),
+        Some(Checkpoint::load(&path).unwrap()),
+        &mut Vec::new(),
+        &mut io::sink(),
+    );
+    assert_eq!(
+        resumed.unwrap_err().to_string(),
+        "checkpoint was saved with different search options"
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+// Levers add to a counter, and the gate needs every one of them
+fn lever_level(levers_needed: i64) -> Value {
+    let lever = |atk| {
+        json!([
+            {"monster": {"hp": 10, "atk": atk, "def": 0}},
+            {"add_counter": {"name": "levers", "value": 1}}
+        ])
+    };
+    json!({
+        "player": {"hp": 100, "atk": 20, "def": 10},
+        "counters": {"levers": 0},
+        "rooms": [
+            {"name": "S"},
+            {"name": "L1", "content": lever(15)},
+            {"name": "L2", "content": lever(16)},
+            {"name": "L3", "content": lever(17)},
+            {"name": "Gate", "content": [
+                {"require_counter": {"name": "levers", "min": levers_needed}}
+            ]},
+            {"name": "E"}
+        ],
+        "arcs": [["S", "L1"], ["S", "L2"], ["S", "L3"], ["S", "Gate"], ["Gate", "E"]],
+        "entrance": "S",
+        "exit": "E"
+    })
+}
+
+#[test]
+fn counter_gate_opens_after_enough_increments() {
+    // Each lever costs HP, but the gate stays closed until all three are pulled
+    let trace = optimal(lever_level(3));
+    assert_eq!(room_names(&trace), ["S", "L1", "L2", "L3", "Gate", "E"]);
+    assert_eq!(trace.stat.hp(), 82);
+
+    let result = solve_level(lever_level(4));
+    assert!(result.optimal.is_none());
+}
+
+// The best branch of the shop and the best config are neither of them the first
+#[test]
+fn best_choice_and_config_are_not_the_first() {
+    let result = solve_level(shop_level());
+    let scores: Vec<f64> = result
+        .configs
+        .iter()
+        .map(|config| config.optimal.as_ref().unwrap().score)
+        .collect();
+    assert_eq!(scores, [123.0, 146.25]);
+
+    let trace = result.optimal.unwrap();
+    assert_eq!(trace.config, 1);
+    assert_eq!(trace.score, 146.25);
+    let shop = trace.trace.iter().find(|step| step.room == "Shop").unwrap();
+    assert_eq!(shop.choice.as_deref(), Some("sword"));
+    assert_eq!(trace.stat.atk(), 22);
+}
+
+// Templates use other templates, and refer to a monster that the level replaces
+// in its catalog, once as it is and once with some stats overridden
+#[test]
+fn templates_expand_with_overrides() {
+    let data = json!({
+        "player": {"hp": 500, "atk": 30, "def": 10, "yk": 2},
+        "catalog": {"monsters": {"Goblin": {"hp": 20, "atk": 20, "def": 5, "gr": 3}}},
+        "templates": {
+            "reward": [{"resource": {"atk": 2}}],
+            "guard": [{"cost": {"yk": 1}}, {"monster": "Goblin"}, {"template": "reward"}],
+            "captain": [
+                {"cost": {"yk": 1}},
+                {"monster": {"name": "Goblin", "hp": 60}},
+                {"template": "reward"}
+            ]
+        },
+        "rooms": [
+            {"name": "S"},
+            {"name": "G1", "content": [{"template": "guard"}]},
+            {"name": "G2", "content": [{"template": "captain"}, {"resource": {"hp": 50}}]},
+            {"name": "E"}
+        ],
+        "arcs": [["S", "G1"], ["G1", "G2"], ["G2", "E"]],
+        "entrance": "S",
+        "exit": "E"
+    });
+    let level_info = LevelInfo::new(data.clone(), &Catalog::builtin()).unwrap();
+    let monsters: Vec<(&str, i32, i16)> = level_info
+        .monsters()
+        .iter()
+        .map(|(name, monster)| (name.as_str(), monster.hp, monster.gr))
+        .collect();
+    assert_eq!(monsters, [("Goblin", 20, 3), ("hp 60 atk 20 def 5", 60, 3)]);
+
+    let trace = optimal(data);
+    let stats: Vec<(&str, i32, i16, i16, i8)> = trace
+        .trace
+        .iter()
+        .map(|step| {
+            let stat = &step.stat;
+            let yk = stat.keys()[0];
+            (step.room.as_str(), stat.hp(), stat.atk(), stat.gr(), yk)
+        })
+        .collect();
+    assert_eq!(
+        stats,
+        [
+            ("S", 500, 30, 0, 2),
+            ("G1", 490, 32, 3, 1),
+            ("G2", 510, 34, 6, 0),
+            ("E", 510, 34, 6, 0)
+        ]
+    );
+}
+
+// Two doors of a key type declared by the level, with a single key for them.
+// The score weighs a spare key like 30 HP.
+fn key_level(keys: i64, door_hp: [i64; 2]) -> Value {
+    json!({
+        "player": {"hp": 100, "atk": 10, "def": 10},
+        "keys": ["yk", "sk"],
+        "score": {"hp": 1000, "sk": 30000},
+        "rooms": [
+            {"name": "S"},
+            {"name": "K", "content": [{"resource": {"sk": keys}}]},
+            {"name": "D1", "content": [{"cost": {"sk": 1}}, {"resource": {"hp": door_hp[0]}}]},
+            {"name": "D2", "content": [{"cost": {"sk": 1}}, {"resource": {"hp": door_hp[1]}}]},
+            {"name": "E"}
+        ],
+        "arcs": [["S", "K"], ["S", "D1"], ["S", "D2"], ["S", "E"]],
+        "entrance": "S",
+        "exit": "E"
+    })
+}
+
+#[test]
+fn key_opens_the_best_door() {
+    let trace = optimal(key_level(1, [50, 100]));
+    assert_eq!(room_names(&trace), ["S", "K", "D2", "E"]);
+    assert_eq!(trace.stat.hp(), 200);
+    assert_eq!(trace.stat.keys()[1], 0);
+    assert_eq!(trace.score, 200.0);
+
+    // Without keys the doors stay locked
+    let trace = optimal(key_level(0, [50, 100]));
+    assert_eq!(room_names(&trace), ["S", "K", "E"]);
+    assert_eq!(trace.stat.hp(), 100);
+
+    // A key is worth more than what is behind the doors
+    let trace = optimal(key_level(1, [10, 20]));
+    assert_eq!(room_names(&trace), ["S", "K", "E"]);
+    assert_eq!(trace.stat.keys()[1], 1);
+    assert_eq!(trace.score, 130.0);
+}
+
+// Drained stats depend on the order of the fights, so players that visited the same rooms
+// are not told apart by HP alone
+#[test]
+fn drain_before_boost_is_kept() {
+    let trace = optimal(json!({
+        "player": {"hp": 1000, "atk": 10, "def": 0},
+        "score": {"hp": 1},
+        "rooms": [
+            {"name": "S"},
+            {"name": "A", "content": [
+                {"monster": {"hp": 50, "atk": 20, "def": 0, "drain_def": 2}},
+                {"resource": {"hp": 200}}
+            ]},
+            {"name": "B", "content": [{"resource": {"def": 10}}]},
+            {"name": "C", "content": [{"monster": {"hp": 100, "atk": 60, "def": 0}}]}
+        ],
+        "arcs": [["S", "A"], ["S", "B"], ["S", "C"]],
+        "entrance": "S",
+        "exit": "C"
+    }));
+
+    // Visiting B first saves 30 HP in A but loses the DEF that saves 100 HP in C
+    assert_eq!(room_names(&trace), ["S", "A", "B", "C"]);
+    assert_eq!(trace.stat.hp(), 600);
+    assert_eq!(trace.stat.def(), 10);
+}
+
+// The weaker LuckyBlade loses more HP to the monster but doubles the GR that opens the gate
+#[test]
+fn double_gr_weapon_opens_the_gate() {
+    let trace = optimal(json!({
+        "player": {"hp": 500, "atk": 10, "def": 10},
+        "score": {"hp": 1},
+        "rooms": [
+            {"name": "S", "content": [{"equipment": "ShortSword"}, {"equipment": "LuckyBlade"}]},
+            {"name": "M", "content": [{"monster": {"hp": 60, "atk": 30, "def": 5, "gr": 10}}]},
+            {"name": "G", "content": [{"cost": {"gr": 20}}, {"resource": {"hp": 200}}]},
+            {"name": "E"}
+        ],
+        "arcs": [["S", "M"], ["M", "G"], ["G", "E"], ["S", "E"]],
+        "entrance": "S",
+        "exit": "E"
+    }));
+
+    assert_eq!(room_names(&trace), ["S", "M", "G", "E"]);
+    assert_eq!(trace.trace[1].equip, ["LuckyBlade"]);
+    assert_eq!(trace.stat.hp(), 640);
+    assert_eq!(trace.stat.atk(), 40);
+}